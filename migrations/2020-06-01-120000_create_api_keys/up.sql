@@ -0,0 +1,10 @@
+-- One row per organisation an API key may query. Keys are never stored in
+-- plain text, only the hex encoded SHA-256 of the key.
+CREATE TABLE ApiKeys (
+  keyHash CHAR(64) NOT NULL,
+  organizationId CHAR(36) NOT NULL,
+  description VARCHAR(255),
+  createdAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  revokedAt DATETIME,
+  PRIMARY KEY (keyHash, organizationId)
+);
@@ -0,0 +1,18 @@
+-- Queries saved under a name by an organisation. Every change to the query is
+-- kept as a new row in SavedQueryVersions, numbered from 1.
+CREATE TABLE SavedQueries (
+  id CHAR(36) NOT NULL PRIMARY KEY,
+  organizationId CHAR(36) NOT NULL,
+  name VARCHAR(255) NOT NULL,
+  createdAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE KEY (organizationId, name)
+);
+CREATE TABLE SavedQueryVersions (
+  savedQueryId CHAR(36) NOT NULL,
+  version INT NOT NULL,
+  query TEXT NOT NULL,
+  createdAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (savedQueryId, version),
+  FOREIGN KEY (savedQueryId) REFERENCES SavedQueries (id) ON DELETE CASCADE
+);
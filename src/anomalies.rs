@@ -0,0 +1,95 @@
+use crate::{
+    answer_query,
+    auth::ApiKey,
+    check_metric_names, check_span,
+    error::ApiError,
+    known_metric_names,
+    logging::{query_span, RequestId},
+    rate_limit::Throttle,
+    settings::Settings,
+    sql_for,
+    telemetry::Telemetry,
+    to_quasr_query, DbConn,
+};
+use chrono::Duration;
+use quasr_core::{
+    anomalies::{find_anomalies, Anomaly, CoreAnomalyMethod, CoreAnomalySettings},
+    input::CoreTimeBreakdown,
+};
+use quasr_io::data_input::json::AdsFlowQuery;
+use rocket::{post, State};
+use rocket_contrib::json::Json;
+
+const DEFAULT_BASELINE_DAYS: u32 = 28;
+const DEFAULT_THRESHOLD: f64 = 3.0;
+const DEFAULT_MIN_OBSERVATIONS: usize = 7;
+
+/// Days of the query's range whose metric values are far off the days before them, for each
+/// series of the query's daily breakdown. Days before the range are fetched as the baseline.
+#[post(
+    "/anomalies?<method>&<baseline_days>&<threshold>&<min_observations>",
+    data = "<query>"
+)]
+pub fn anomalies(
+    query: Json<AdsFlowQuery>,
+    method: Option<String>,
+    baseline_days: Option<u32>,
+    threshold: Option<f64>,
+    min_observations: Option<usize>,
+    key: ApiKey,
+    throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+    telemetry: State<Telemetry>,
+    request_id: RequestId,
+) -> Result<Json<Vec<Anomaly>>, ApiError> {
+    let query = query.into_inner();
+    key.authorize(query.org_id())?;
+    let _permit = throttle.acquire(query.org_id())?;
+    let anomaly_settings = CoreAnomalySettings {
+        method: match method.as_deref().unwrap_or("zScore") {
+            "zScore" => CoreAnomalyMethod::ZScore,
+            "mad" => CoreAnomalyMethod::MedianAbsoluteDeviation,
+            other => {
+                return Err(ApiError::bad_request(&format!(
+                    "Unknown method {}, it has to be zScore or mad",
+                    other
+                )))
+            }
+        },
+        baseline_days: baseline_days.unwrap_or(DEFAULT_BASELINE_DAYS),
+        threshold: threshold.unwrap_or(DEFAULT_THRESHOLD),
+        min_observations: min_observations.unwrap_or(DEFAULT_MIN_OBSERVATIONS),
+    };
+    if anomaly_settings.baseline_days == 0
+        || !anomaly_settings.threshold.is_finite()
+        || anomaly_settings.threshold <= 0.0
+    {
+        return Err(ApiError::bad_request(
+            "The baseline and the threshold have to be positive numbers",
+        ));
+    }
+    let mut q = to_quasr_query(query, &settings)?;
+    if q.time_breakdown != Some(CoreTimeBreakdown::Day) {
+        return Err(ApiError::bad_request(
+            "Anomalies can only be found in a daily time breakdown",
+        ));
+    }
+    let _span = query_span(&request_id, &q).entered();
+    check_metric_names(
+        &q,
+        &known_metric_names(&conn, &q.org_id, &settings.schema)?,
+        false,
+    )?;
+    let report_start = q.start_date;
+    q.start_date = report_start
+        .checked_sub_signed(Duration::days(anomaly_settings.baseline_days.into()))
+        .ok_or_else(|| ApiError::bad_request("The baseline starts too long ago"))?;
+    q.include_metadata = false;
+    // The baseline counts towards the days the query can span
+    check_span(&q, &settings)?;
+    let sql_query = sql_for(&q, &settings.schema);
+    let limits = &settings.org(&q.org_id).limits;
+    let rows = answer_query(&conn, q, sql_query, &settings.schema, limits, &telemetry)?;
+    Ok(Json(find_anomalies(&rows, &anomaly_settings, report_start)))
+}
@@ -0,0 +1,70 @@
+use crate::DbConn;
+use quasr_io::data_input::mysql::credentials::{hash_api_key, load_org_ids_for_api_key};
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ApiKeyError {
+    Missing,
+    Invalid,
+    Database,
+}
+/// An API key, sent either as `Authorization: Bearer <key>` or as `X-Api-Key: <key>`,
+/// together with the organisations it may query
+#[derive(Clone)]
+pub struct ApiKey {
+    pub key_hash: String,
+    pub org_ids: HashSet<String>,
+}
+impl ApiKey {
+    pub fn authorize(&self, org_id: &str) -> Result<(), Status> {
+        if self.org_ids.contains(org_id) {
+            Ok(())
+        } else {
+            Err(Status::Forbidden)
+        }
+    }
+}
+pub fn key_from_headers<'a>(request: &'a Request) -> Option<&'a str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .or_else(|| request.headers().get_one("X-Api-Key"))
+        .map(|k| k.trim())
+        .filter(|k| !k.is_empty())
+}
+/// Looks up the request's key
+fn check_key(request: &Request) -> Result<ApiKey, (Status, ApiKeyError)> {
+    let key = key_from_headers(request).ok_or((Status::Unauthorized, ApiKeyError::Missing))?;
+    let conn = match request.guard::<DbConn>() {
+        Outcome::Success(conn) => conn,
+        Outcome::Failure((status, _)) => return Err((status, ApiKeyError::Database)),
+        Outcome::Forward(()) => return Err((Status::ServiceUnavailable, ApiKeyError::Database)),
+    };
+    let key_hash = hash_api_key(key);
+    match load_org_ids_for_api_key(&conn, &key_hash) {
+        Ok(org_ids) if org_ids.is_empty() => Err((Status::Unauthorized, ApiKeyError::Invalid)),
+        Ok(org_ids) => Ok(ApiKey {
+            key_hash,
+            org_ids: org_ids.into_iter().collect(),
+        }),
+        Err(_) => Err((Status::ServiceUnavailable, ApiKeyError::Database)),
+    }
+}
+/// The key is only looked up once per request, however many guards ask for it
+struct CheckedKey(Result<ApiKey, (Status, ApiKeyError)>);
+impl<'a, 'r> FromRequest<'a, 'r> for ApiKey {
+    type Error = ApiKeyError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match &request.local_cache(|| CheckedKey(check_key(request))).0 {
+            Ok(key) => Outcome::Success(key.clone()),
+            Err(e) => Outcome::Failure(*e),
+        }
+    }
+}
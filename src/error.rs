@@ -0,0 +1,58 @@
+use quasr_io::data_input::mysql::QueryError;
+use rocket::{
+    http::Status,
+    request::Request,
+    response::{self, Responder, Response},
+};
+use rocket_contrib::json::Json;
+use serde_json::{json, Value};
+
+/// An error returned to the client as `{"error": message}` with the given status
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: Status,
+    pub body: Value,
+    /// Seconds sent as `Retry-After`
+    pub retry_after: Option<u64>,
+}
+impl ApiError {
+    pub fn new(status: Status, message: &str) -> Self {
+        ApiError {
+            status,
+            body: json!({ "error": message }),
+            retry_after: None,
+        }
+    }
+    pub fn bad_request(message: &str) -> Self {
+        ApiError::new(Status::BadRequest, message)
+    }
+    pub fn internal(message: &str) -> Self {
+        ApiError::new(Status::InternalServerError, message)
+    }
+}
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        ApiError::new(status, status.reason)
+    }
+}
+impl From<QueryError> for ApiError {
+    fn from(e: QueryError) -> Self {
+        match e {
+            QueryError::TooManyRows { .. } => {
+                ApiError::new(Status::UnprocessableEntity, &e.to_string())
+            }
+            QueryError::Timeout => ApiError::new(Status::GatewayTimeout, &e.to_string()),
+            QueryError::Database(_) => ApiError::internal(&e.to_string()),
+        }
+    }
+}
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = Response::build_from(Json(self.body).respond_to(request)?);
+        response.status(self.status);
+        if let Some(seconds) = self.retry_after {
+            response.raw_header("Retry-After", seconds.to_string());
+        }
+        response.ok()
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{answer_query, logging::query_hash, settings::Settings, sql_for, telemetry::Telemetry};
+use diesel::{mysql::MysqlConnection, Connection};
+use quasr_core::{input::QuasrQuery, limits::CoreQueryLimits, schema::SchemaMapping};
+use quasr_io::output_csv::qs_rows_to_string;
+use rocket::{fairing::AdHoc, Rocket};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::info_span;
+use uuid::Uuid;
+
+const DEFAULT_WORKERS: i64 = 2;
+const DEFAULT_TTL_HOURS: i64 = 24;
+/// How often expired jobs and their results are deleted
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: String,
+    #[serde(skip)]
+    pub org_id: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Seconds since the epoch
+    pub created_at: u64,
+    /// After this the job and its result are deleted
+    pub expires_at: u64,
+}
+type JobMap = Arc<Mutex<HashMap<String, Job>>>;
+/// Id of a queued job, its query and the limits of its organisation
+type QueuedJob = (String, QuasrQuery, CoreQueryLimits);
+/// Queue of export jobs, answered in the background by a pool of worker threads.
+/// Results are written as CSV files to `dir` and deleted once the job expires, by a thread that
+/// sweeps the directory every `SWEEP_INTERVAL`.
+pub struct JobQueue {
+    sender: Mutex<Sender<QueuedJob>>,
+    jobs: JobMap,
+    dir: PathBuf,
+    ttl: Duration,
+}
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+fn result_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.csv", id))
+}
+fn update(jobs: &JobMap, id: &str, f: impl FnOnce(&mut Job)) {
+    if let Some(job) = jobs.lock().unwrap().get_mut(id) {
+        f(job)
+    }
+}
+fn run_job(
+    jobs: &JobMap,
+    dir: &Path,
+    database_url: &str,
+    id: &str,
+    query: QuasrQuery,
+    limits: &CoreQueryLimits,
+    schema: &SchemaMapping,
+    telemetry: &Telemetry,
+) -> Result<(), String> {
+    let _span = info_span!(
+        "job",
+        job_id = %id,
+        org_id = %query.org_id,
+        query_hash = %query_hash(&query)
+    )
+    .entered();
+    update(jobs, id, |j| j.status = JobStatus::Running);
+    let conn = MysqlConnection::establish(database_url).map_err(|e| e.to_string())?;
+    let sql_query = sql_for(&query, schema);
+    let rows = answer_query(&conn, query, sql_query, schema, limits, telemetry)
+        .map_err(|e| e.to_string())?;
+    fs::write(result_path(dir, id), qs_rows_to_string(rows)).map_err(|e| e.to_string())
+}
+fn worker(
+    receiver: Arc<Mutex<Receiver<QueuedJob>>>,
+    jobs: JobMap,
+    dir: PathBuf,
+    url: String,
+    schema: SchemaMapping,
+    telemetry: Telemetry,
+) {
+    loop {
+        let received = receiver.lock().unwrap().recv();
+        let (id, query, limits) = match received {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // Loading panics on database errors, which shouldn't take the worker down with it
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_job(&jobs, &dir, &url, &id, query, &limits, &schema, &telemetry)
+        }))
+        .unwrap_or_else(|_| Err("The query failed".to_owned()));
+        update(&jobs, &id, |j| match result {
+            Ok(()) => j.status = JobStatus::Done,
+            Err(e) => {
+                j.status = JobStatus::Failed;
+                j.error = Some(e);
+            }
+        });
+    }
+}
+/// Forgets the expired jobs, and deletes the results older than `ttl`. Results are found by
+/// their files' age, so the ones left by jobs from before a restart are deleted too.
+fn sweep(jobs: &JobMap, dir: &Path, ttl: Duration) {
+    let now = SystemTime::now();
+    jobs.lock()
+        .unwrap()
+        .retain(|_, job| job.expires_at > seconds_since_epoch(now));
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let expired = path.extension().map_or(false, |e| e == "csv")
+            && fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .map_or(false, |age| age >= ttl);
+        if expired {
+            fs::remove_file(&path).ok();
+        }
+    }
+}
+impl JobQueue {
+    pub fn new(
+        workers: usize,
+        dir: PathBuf,
+        ttl: Duration,
+        database_url: String,
+        schema: SchemaMapping,
+        telemetry: Telemetry,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let jobs: JobMap = Arc::new(Mutex::new(HashMap::new()));
+        for _ in 0..workers {
+            let (receiver, jobs, dir, url, schema, telemetry) = (
+                receiver.clone(),
+                jobs.clone(),
+                dir.clone(),
+                database_url.clone(),
+                schema.clone(),
+                telemetry.clone(),
+            );
+            thread::spawn(move || worker(receiver, jobs, dir, url, schema, telemetry));
+        }
+        sweep(&jobs, &dir, ttl);
+        let (sweep_jobs, sweep_dir) = (jobs.clone(), dir.clone());
+        thread::spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            sweep(&sweep_jobs, &sweep_dir, ttl);
+        });
+        JobQueue {
+            sender: Mutex::new(sender),
+            jobs,
+            dir,
+            ttl,
+        }
+    }
+    /// Reads `jobs_dir`, `job_workers` and `job_ttl_hours` from the config. Has to be attached
+    /// after the `Settings`, for their schema mapping.
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_attach("Job queue", |rocket: Rocket| {
+            let config = rocket.config();
+            let url = match rocket_contrib::databases::database_config("test_db", config) {
+                Ok(db) => db.url.to_owned(),
+                Err(_) => return Err(rocket),
+            };
+            let schema = match rocket.state::<Settings>() {
+                Some(settings) => settings.schema.clone(),
+                None => return Err(rocket),
+            };
+            let dir = PathBuf::from(config.get_str("jobs_dir").unwrap_or("jobs"));
+            if fs::create_dir_all(&dir).is_err() {
+                return Err(rocket);
+            }
+            let workers = config.get_int("job_workers").unwrap_or(DEFAULT_WORKERS);
+            let ttl_hours = config.get_int("job_ttl_hours").unwrap_or(DEFAULT_TTL_HOURS);
+            let queue = JobQueue::new(
+                workers.max(1) as usize,
+                dir,
+                Duration::from_secs(ttl_hours.max(0) as u64 * 3600),
+                url,
+                schema,
+                // Shared with the server when its telemetry fairing is attached first
+                rocket.state::<Telemetry>().cloned().unwrap_or_default(),
+            );
+            Ok(rocket.manage(queue))
+        })
+    }
+    /// Queues `query`, to be answered within `limits`
+    pub fn submit(&self, query: QuasrQuery, limits: CoreQueryLimits) -> Job {
+        let now = SystemTime::now();
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            org_id: query.org_id.clone(),
+            status: JobStatus::Queued,
+            error: None,
+            created_at: seconds_since_epoch(now),
+            expires_at: seconds_since_epoch(now + self.ttl),
+        };
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(job.id.clone(), job.clone());
+        self.sender
+            .lock()
+            .unwrap()
+            .send((job.id.clone(), query, limits))
+            .unwrap();
+        job
+    }
+    /// The job, unless it has expired. The sweep may not have deleted it yet.
+    pub fn get(&self, id: &str) -> Option<Job> {
+        let now = seconds_since_epoch(SystemTime::now());
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|job| job.expires_at > now)
+            .cloned()
+    }
+    pub fn result_path(&self, id: &str) -> PathBuf {
+        result_path(&self.dir, id)
+    }
+}
@@ -0,0 +1,100 @@
+use quasr_core::input::QuasrQuery;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    request::{self, FromRequest},
+    Data, Outcome, Request, Response,
+};
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+use tracing::{info, info_span, Span};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use uuid::Uuid;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Logs JSON lines to stdout, filtered by `RUST_LOG` (`info` by default). Spans are logged when
+/// they close, with how long they took.
+pub fn init() {
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+}
+/// Id of the request, taken from its `X-Request-Id` header or generated. It is sent back in
+/// the same header of the response.
+#[derive(Clone)]
+pub struct RequestId {
+    pub id: String,
+    started: Instant,
+}
+impl RequestId {
+    pub fn of(request: &Request) -> RequestId {
+        request
+            .local_cache(|| RequestId {
+                id: request
+                    .headers()
+                    .get_one(REQUEST_ID_HEADER)
+                    .filter(|id| {
+                        !id.is_empty()
+                            && id.len() <= 64
+                            && id
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    })
+                    .map(|id| id.to_owned())
+                    .unwrap_or_else(|| Uuid::new_v4().to_string()),
+                started: Instant::now(),
+            })
+            .clone()
+    }
+}
+impl<'a, 'r> FromRequest<'a, 'r> for RequestId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
+/// Stable for the same query across requests and restarts, to tell repeated queries apart in
+/// the logs
+pub fn query_hash(q: &QuasrQuery) -> String {
+    let json = serde_json::to_string(q).unwrap_or_default();
+    format!("{:x}", Sha256::digest(json.as_bytes()))[..16].to_owned()
+}
+/// Span answering `q` is logged in
+pub fn query_span(request_id: &RequestId, q: &QuasrQuery) -> Span {
+    info_span!(
+        "query",
+        request_id = %request_id.id,
+        org_id = %q.org_id,
+        query_hash = %query_hash(q)
+    )
+}
+/// Assigns every request an id, and logs a line for every response
+pub struct RequestLogger;
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        RequestId::of(request);
+    }
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let request_id = RequestId::of(request);
+        response.set_raw_header(REQUEST_ID_HEADER, request_id.id.clone());
+        info!(
+            request_id = %request_id.id,
+            method = %request.method(),
+            path = %request.uri().path(),
+            status = response.status().code,
+            duration_ms = request_id.started.elapsed().as_millis() as u64,
+            "Request answered"
+        );
+    }
+}
@@ -1,53 +1,516 @@
 #![feature(proc_macro_hygiene, decl_macro)]
+mod anomalies;
+mod auth;
+mod error;
+mod jobs;
+mod logging;
+mod output;
+mod pacing;
+mod rate_limit;
+mod saved_queries;
+mod settings;
+mod telemetry;
+use auth::ApiKey;
+use chrono::NaiveDate;
+use diesel::mysql::MysqlConnection;
 use dotenv;
-use quasr_core::{build_sql, input::QuasrQuery, metrics_to_indexed_metrics, OutputDataRow};
+use error::ApiError;
+use jobs::{Job, JobQueue, JobStatus};
+use logging::{query_span, RequestId, RequestLogger};
+use output::OutputFormat;
+use quasr_core::{
+    attach_node_details,
+    batch::batch_queries,
+    build_node_details_sql, build_sql,
+    input::{InputDataRow, QuasrQuery},
+    limits::CoreQueryLimits,
+    metrics_to_indexed_metrics, output_marketing_nodes,
+    schema::SchemaMapping,
+    validation::find_unknown_metrics,
+    CoreSqlString, OutputDataRow,
+};
 use quasr_io::{
-    data_input::{json::AdsFlowQuery, mysql::load_query_from_db},
-    output_csv::qs_rows_to_string,
+    data_input::{
+        json::{AdsFlowQuery, BatchQuery},
+        mysql::{
+            catalog::{load_known_metrics, load_metric_catalog, MetricField},
+            explain_query_in_db,
+            hierarchy::{load_hierarchy, HierarchyFilter, HierarchyNode},
+            load_node_details_from_db, load_query_from_db, QueryError,
+        },
+    },
+    output_csv::QueryServerRow,
 };
+use rate_limit::{RateLimiter, Throttle};
 use rocket::{
-    http::ContentType,
+    catchers, get,
+    http::{ContentType, Status},
     post,
     response::{
-        Responder, Response, {self},
+        status::Accepted,
+        NamedFile, Responder, Response, {self},
     },
-    routes, Request,
+    routes, Request, State,
 };
 use rocket_contrib::{database, json::Json};
-use serde_json;
-use std::{convert::TryInto, io::Cursor};
+use serde_json::{json, Value};
+use settings::Settings;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryInto,
+    io::Cursor,
+};
+use telemetry::{Phase, Telemetry};
+use tracing::{field, info, info_span};
 #[database("test_db")]
-struct DbConn(diesel::mysql::MysqlConnection);
+struct DbConn(MysqlConnection);
 struct QSResponse {
     r: Vec<OutputDataRow>,
+    /// Sent back as `Warning` headers
+    warnings: Vec<String>,
+    query: QuasrQuery,
+    /// The `?format=` parameter, the `Accept` header is used without it
+    format: Option<String>,
+    /// Metrics that are amounts of money, for the formats that format them
+    currency_metrics: HashSet<String>,
 }
 impl<'r> Responder<'r> for QSResponse {
-    fn respond_to(self, _: &Request) -> response::Result<'r> {
-        Response::build()
-            .sized_body(Cursor::new(qs_rows_to_string(self.r)))
-            .header(ContentType::parse_flexible("text/csv").unwrap())
-            .raw_header("Vary", "Accept-Encoding")
-            .ok()
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let format = match OutputFormat::negotiate(self.format.as_deref(), request) {
+            Ok(format) => format,
+            Err(e) => return e.respond_to(request),
+        };
+        let span = info_span!(
+            "serialize",
+            request_id = %RequestId::of(request).id,
+            rows = self.r.len(),
+            format = ?format
+        );
+        let body = match span.in_scope(|| format.write(self.r, &self.query, &self.currency_metrics))
+        {
+            Ok(body) => body,
+            Err(e) => return ApiError::internal(&e).respond_to(request),
+        };
+        let mut response = Response::build();
+        response
+            .sized_body(Cursor::new(body))
+            .header(format.content_type())
+            .raw_header("Vary", "Accept, Accept-Encoding");
+        for warning in self.warnings {
+            response.raw_header_adjoin(
+                "Warning",
+                format!("299 quasr \"{}\"", warning.replace('"', "'")),
+            );
+        }
+        response.ok()
     }
 }
-#[post("/", data = "<query>")]
-fn index(query: Json<AdsFlowQuery>, conn: DbConn) -> QSResponse {
-    let q = query.into_inner();
-    println!("{}", serde_json::to_string_pretty(&q).unwrap());
-    let q: QuasrQuery = q.try_into().unwrap();
-    let sql_query = build_sql(&q);
-    let db_rows = load_query_from_db(&conn, sql_query);
+/// Converts the query, resolving relative date ranges and time buckets with the organisation's
+/// settings
+fn to_quasr_query(mut query: AdsFlowQuery, settings: &Settings) -> Result<QuasrQuery, ApiError> {
+    query.resolve_dates(settings.today(query.org_id()));
+    let mut q = TryInto::<QuasrQuery>::try_into(query)
+        .map_err(|e| ApiError::bad_request(&e.to_string()))?;
+    q.calendar = settings.org(&q.org_id).calendar.clone();
+    check_span(&q, settings)?;
+    Ok(q)
+}
+/// Checks the days the query spans against the organisation's limits
+fn check_span(q: &QuasrQuery, settings: &Settings) -> Result<(), ApiError> {
+    settings
+        .org(&q.org_id)
+        .limits
+        .check_span(q)
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, &e))
+}
+fn parse_date(date: Option<String>) -> Result<Option<NaiveDate>, ApiError> {
+    date.map(|d| {
+        NaiveDate::parse_from_str(&d, "%Y-%m-%d")
+            .map_err(|_| ApiError::bad_request(&format!("{} is not a YYYY-MM-DD date", d)))
+    })
+    .transpose()
+}
+fn known_metric_names(
+    conn: &DbConn,
+    org_id: &str,
+    schema: &SchemaMapping,
+) -> Result<HashSet<String>, ApiError> {
+    known_metrics(conn, org_id, schema).map(|(known, _)| known)
+}
+/// The names of the organisation's metrics, and of the ones that are amounts of money
+fn known_metrics(
+    conn: &DbConn,
+    org_id: &str,
+    schema: &SchemaMapping,
+) -> Result<(HashSet<String>, HashSet<String>), ApiError> {
+    let metrics =
+        load_known_metrics(conn, org_id, schema).map_err(|e| ApiError::internal(&e.to_string()))?;
+    let currency = metrics
+        .iter()
+        .filter(|m| m.has_currency)
+        .map(|m| m.name.clone())
+        .collect();
+    Ok((metrics.into_iter().map(|m| m.name).collect(), currency))
+}
+/// Checks the query's metrics against the ones the organisation has. Unknown metrics are an
+/// error, unless `lenient` is set, in which case they are returned as warnings.
+fn check_metric_names(
+    q: &QuasrQuery,
+    known: &HashSet<String>,
+    lenient: bool,
+) -> Result<Vec<String>, ApiError> {
+    let unknown = find_unknown_metrics(q, known);
+    if unknown.is_empty() {
+        Ok(vec![])
+    } else if lenient {
+        Ok(unknown
+            .iter()
+            .map(|u| match u.suggestions.first() {
+                Some(suggestion) => {
+                    format!("Unknown metric {} (did you mean {}?)", u.name, suggestion)
+                }
+                None => format!("Unknown metric {}", u.name),
+            })
+            .collect())
+    } else {
+        Err(ApiError {
+            status: Status::BadRequest,
+            body: json!({ "error": "Unknown metrics", "unknownMetrics": unknown }),
+            retry_after: None,
+        })
+    }
+}
+/// `build_sql`, in its own span
+fn sql_for(q: &QuasrQuery, schema: &SchemaMapping) -> CoreSqlString {
+    info_span!("build_sql").in_scope(|| build_sql(q, schema))
+}
+/// Runs the SQL within the row and time limits, recording how long it took and how many rows
+/// it returned
+fn fetch_rows(
+    conn: &MysqlConnection,
+    sql_query: CoreSqlString,
+    limits: &CoreQueryLimits,
+    telemetry: &Telemetry,
+) -> Result<Vec<InputDataRow>, QueryError> {
+    let sql_query = match limits.max_execution_time_ms {
+        Some(ms) => sql_query.with_max_execution_time(ms),
+        None => sql_query,
+    };
+    let span = info_span!("load_rows", rows = field::Empty);
+    let db_rows = span.in_scope(|| {
+        telemetry.time(Phase::Sql, || {
+            load_query_from_db(conn, sql_query, limits.max_rows)
+        })
+    })?;
+    span.record("rows", &db_rows.len());
+    telemetry.observe_rows(db_rows.len());
+    Ok(db_rows)
+}
+/// Runs the SQL built for `q` and computes its metrics from the rows
+fn answer_query(
+    conn: &MysqlConnection,
+    q: QuasrQuery,
+    sql_query: CoreSqlString,
+    schema: &SchemaMapping,
+    limits: &CoreQueryLimits,
+    telemetry: &Telemetry,
+) -> Result<Vec<OutputDataRow>, QueryError> {
+    let db_rows = fetch_rows(conn, sql_query, limits, telemetry)?;
     // let db_rows = InputDataRow::mock();
-    let qs_rows = metrics_to_indexed_metrics(q, db_rows);
+    let include_metadata = q.include_metadata && q.marketing_node_breakdown.is_some();
+    let org_id = q.org_id.clone();
+    let span = info_span!("process_metrics", rows = field::Empty);
+    let mut qs_rows = span.in_scope(|| {
+        telemetry.time(Phase::Processing, || {
+            metrics_to_indexed_metrics(q, &db_rows)
+        })
+    });
+    span.record("rows", &qs_rows.len());
+    if include_metadata {
+        add_node_metadata(conn, &org_id, &mut qs_rows, schema)?;
+    }
+    Ok(qs_rows)
+}
+fn add_node_metadata(
+    conn: &MysqlConnection,
+    org_id: &str,
+    rows: &mut Vec<OutputDataRow>,
+    schema: &SchemaMapping,
+) -> Result<(), QueryError> {
+    let nodes = output_marketing_nodes(rows);
+    if !nodes.is_empty() {
+        let details =
+            load_node_details_from_db(conn, build_node_details_sql(org_id, &nodes, schema))?;
+        attach_node_details(rows, &details);
+    }
+    Ok(())
+}
+#[post("/?<lenient>&<format>", data = "<query>")]
+fn index(
+    query: Json<AdsFlowQuery>,
+    key: ApiKey,
+    throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+    telemetry: State<Telemetry>,
+    request_id: RequestId,
+    lenient: Option<bool>,
+    format: Option<String>,
+) -> Result<QSResponse, ApiError> {
+    let q = query.into_inner();
+    key.authorize(q.org_id())?;
+    let _permit = throttle.acquire(q.org_id())?;
+    let q = to_quasr_query(q, &settings)?;
+    let _span = query_span(&request_id, &q).entered();
+    let (known, currency_metrics) = known_metrics(&conn, &q.org_id, &settings.schema)?;
+    let warnings = check_metric_names(&q, &known, lenient.unwrap_or(false))?;
+    let sql_query = sql_for(&q, &settings.schema);
+    let limits = &settings.org(&q.org_id).limits;
+    let qs_rows = answer_query(
+        &conn,
+        q.clone(),
+        sql_query,
+        &settings.schema,
+        limits,
+        &telemetry,
+    )?;
+    info!(rows = qs_rows.len(), "Query answered");
     // let csv_rows: Vec<QueryServerRow> = qs_rows.into_iter().map(|r| r.into()).collect();
-    QSResponse { r: qs_rows }
+    Ok(QSResponse {
+        r: qs_rows,
+        warnings,
+        query: q,
+        format,
+        currency_metrics,
+    })
     // Content(ContentType::parse_flexible("text/csv").unwrap(), csv_rows)
 }
+/// Answers several queries at once, keyed by their id. Queries that only differ in their metrics
+/// are answered from a single database query. Unknown metrics fail the whole batch.
+#[post("/batch", data = "<queries>")]
+fn batch(
+    queries: Json<Vec<BatchQuery>>,
+    key: ApiKey,
+    throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+    telemetry: State<Telemetry>,
+    request_id: RequestId,
+) -> Result<Json<BTreeMap<String, Vec<QueryServerRow>>>, ApiError> {
+    let mut ids: Vec<String> = vec![];
+    let mut core_queries: Vec<QuasrQuery> = vec![];
+    let mut known_by_org: HashMap<String, HashSet<String>> = HashMap::new();
+    // One request and one query slot for every organisation in the batch
+    let mut permits = HashMap::new();
+    for BatchQuery { id, query } in queries.into_inner() {
+        if ids.contains(&id) {
+            return Err(ApiError::bad_request(&format!("Duplicate query id {}", id)));
+        }
+        key.authorize(query.org_id())?;
+        if !permits.contains_key(query.org_id()) {
+            let permit = throttle.acquire(query.org_id())?;
+            permits.insert(query.org_id().to_owned(), permit);
+        }
+        let with_id = |mut e: ApiError| {
+            e.body["id"] = json!(id);
+            e
+        };
+        let q = to_quasr_query(query, &settings).map_err(with_id)?;
+        if !known_by_org.contains_key(&q.org_id) {
+            let known = known_metric_names(&conn, &q.org_id, &settings.schema)?;
+            known_by_org.insert(q.org_id.clone(), known);
+        }
+        check_metric_names(&q, &known_by_org[&q.org_id], false).map_err(with_id)?;
+        ids.push(id);
+        core_queries.push(q);
+    }
+    let mut results = BTreeMap::new();
+    for query_batch in batch_queries(core_queries) {
+        let _span = query_span(&request_id, &query_batch.scan).entered();
+        let db_rows = fetch_rows(
+            &conn,
+            sql_for(&query_batch.scan, &settings.schema),
+            &settings.org(&query_batch.scan.org_id).limits,
+            &telemetry,
+        )?;
+        for (idx, q) in query_batch.queries {
+            let _span = info_span!("batch_query", id = %ids[idx]).entered();
+            let include_metadata = q.include_metadata && q.marketing_node_breakdown.is_some();
+            let org_id = q.org_id.clone();
+            let mut qs_rows = telemetry.time(Phase::Processing, || {
+                metrics_to_indexed_metrics(q, &db_rows)
+            });
+            if include_metadata {
+                add_node_metadata(&conn, &org_id, &mut qs_rows, &settings.schema)?;
+            }
+            results.insert(
+                ids[idx].clone(),
+                qs_rows.into_iter().map(QueryServerRow::from).collect(),
+            );
+        }
+    }
+    Ok(Json(results))
+}
+/// Starts answering the query in the background, for exports that take too long for a request
+#[post("/jobs", data = "<query>")]
+fn create_job(
+    query: Json<AdsFlowQuery>,
+    key: ApiKey,
+    throttle: Throttle,
+    conn: DbConn,
+    queue: State<JobQueue>,
+    settings: State<Settings>,
+) -> Result<Accepted<Json<Job>>, ApiError> {
+    let q = query.into_inner();
+    key.authorize(q.org_id())?;
+    // Only counts against the request rate, the job queue bounds how many jobs run at once
+    drop(throttle.acquire(q.org_id())?);
+    let q = to_quasr_query(q, &settings)?;
+    let known = known_metric_names(&conn, &q.org_id, &settings.schema)?;
+    check_metric_names(&q, &known, false)?;
+    let limits = settings.org(&q.org_id).limits.clone();
+    Ok(Accepted(Some(Json(queue.submit(q, limits)))))
+}
+fn find_job(queue: &JobQueue, key: &ApiKey, id: &str) -> Result<Job, ApiError> {
+    let job = queue.get(id).ok_or(Status::NotFound)?;
+    // Jobs of other organisations are reported as missing, not forbidden
+    key.authorize(&job.org_id).map_err(|_| Status::NotFound)?;
+    Ok(job)
+}
+#[get("/jobs/<id>")]
+fn job_status(id: String, key: ApiKey, queue: State<JobQueue>) -> Result<Json<Job>, ApiError> {
+    find_job(&queue, &key, &id).map(Json)
+}
+/// The CSV the job produced, in the same format as answering the query directly
+#[get("/jobs/<id>/result")]
+fn job_result(id: String, key: ApiKey, queue: State<JobQueue>) -> Result<NamedFile, ApiError> {
+    let job = find_job(&queue, &key, &id)?;
+    match job.status {
+        JobStatus::Done => NamedFile::open(queue.result_path(&job.id))
+            .map_err(|_| ApiError::new(Status::Gone, "The result has expired")),
+        JobStatus::Failed => Err(ApiError::new(
+            Status::Conflict,
+            job.error.as_deref().unwrap_or("The job failed"),
+        )),
+        JobStatus::Queued | JobStatus::Running => Err(ApiError::new(
+            Status::Conflict,
+            "The job hasn't finished yet",
+        )),
+    }
+}
+
+/// Shows how a query would be answered without fetching any data: the normalized query, the SQL
+/// it is translated to, with the values bound to its placeholders in `params`, and the metrics it
+/// needs. With `?plan=true` the database's plan for the SQL is included as well.
+#[post("/explain?<plan>", data = "<query>")]
+fn explain(
+    query: Json<AdsFlowQuery>,
+    key: ApiKey,
+    throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+    plan: Option<bool>,
+) -> Result<Json<Value>, ApiError> {
+    let q = query.into_inner();
+    key.authorize(q.org_id())?;
+    let _permit = throttle.acquire(q.org_id())?;
+    let q = to_quasr_query(q, &settings)?;
+    let sql_query = sql_for(&q, &settings.schema);
+    let plan = if plan.unwrap_or(false) {
+        Some(
+            explain_query_in_db(&conn, &sql_query)
+                .map_err(|e| ApiError::internal(&e.to_string()))?,
+        )
+    } else {
+        None
+    };
+    let mut base_metrics: Vec<String> = q.base_metric_names().into_iter().collect();
+    base_metrics.sort();
+    Ok(Json(json!({
+        "query": q,
+        "sql": sql_query.as_str(),
+        "params": sql_query.params(),
+        "baseMetrics": base_metrics,
+        "plan": plan,
+    })))
+}
+/// Metrics the organisation has configured, and the days there is data for them
+#[get("/orgs/<org_id>/metrics")]
+fn metric_catalog(
+    org_id: String,
+    key: ApiKey,
+    _throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+) -> Result<Json<Vec<MetricField>>, ApiError> {
+    key.authorize(&org_id)?;
+    load_metric_catalog(&conn, &org_id, &settings.schema)
+        .map(Json)
+        .map_err(|e| ApiError::internal(&e.to_string()))
+}
+/// The campaign -> ad set -> ad tree of the organisation, for building filter pickers
+#[get("/orgs/<org_id>/nodes?<platform>&<status>&<prefix>&<last_activity>")]
+fn node_hierarchy(
+    org_id: String,
+    platform: Option<String>,
+    status: Option<String>,
+    prefix: Option<String>,
+    last_activity: Option<bool>,
+    key: ApiKey,
+    _throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+) -> Result<Json<Vec<HierarchyNode>>, ApiError> {
+    key.authorize(&org_id)?;
+    let filter = HierarchyFilter {
+        platform,
+        status,
+        name_prefix: prefix,
+    };
+    load_hierarchy(
+        &conn,
+        &org_id,
+        &filter,
+        last_activity.unwrap_or(false),
+        &settings.schema,
+    )
+    .map(Json)
+    .map_err(ApiError::from)
+}
 
 fn main() {
     dotenv::dotenv().ok();
+    logging::init();
     rocket::ignite()
+        .attach(RequestLogger)
+        .attach(Telemetry::default())
         .attach(DbConn::fairing())
-        .mount("/", routes![index])
+        .attach(Settings::fairing())
+        .attach(JobQueue::fairing())
+        .manage(RateLimiter::default())
+        .mount(
+            "/",
+            routes![
+                index,
+                batch,
+                create_job,
+                job_status,
+                job_result,
+                explain,
+                metric_catalog,
+                node_hierarchy,
+                pacing::pacing,
+                anomalies::anomalies,
+                telemetry::metrics,
+                saved_queries::create,
+                saved_queries::list,
+                saved_queries::show,
+                saved_queries::versions,
+                saved_queries::update,
+                saved_queries::remove,
+                saved_queries::run
+            ],
+        )
+        .register(catchers![rate_limit::rejected])
         .launch();
 }
@@ -0,0 +1,85 @@
+use crate::error::ApiError;
+use quasr_core::{input::QuasrQuery, OutputDataRow};
+use quasr_io::{
+    output_arrow::qs_rows_to_arrow_stream, output_csv::qs_rows_to_string,
+    output_parquet::qs_rows_to_parquet, output_xlsx::qs_rows_to_xlsx,
+};
+use rocket::{
+    http::{ContentType, MediaType},
+    Request,
+};
+use std::collections::HashSet;
+
+/// Formats query results can be sent back in
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Parquet,
+    /// The Arrow IPC streaming format
+    Arrow,
+    /// An Excel workbook with a column per metric
+    Xlsx,
+}
+impl OutputFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(OutputFormat::Csv),
+            "parquet" => Some(OutputFormat::Parquet),
+            "arrow" => Some(OutputFormat::Arrow),
+            "xlsx" => Some(OutputFormat::Xlsx),
+            _ => None,
+        }
+    }
+    fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        [
+            OutputFormat::Csv,
+            OutputFormat::Parquet,
+            OutputFormat::Arrow,
+            OutputFormat::Xlsx,
+        ]
+        .iter()
+        .copied()
+        .find(|format| format.content_type().media_type() == media_type)
+    }
+    /// The `?format=` parameter if there is one, otherwise the first format the `Accept` header
+    /// lists. CSV when neither picks one.
+    pub fn negotiate(format: Option<&str>, request: &Request) -> Result<Self, ApiError> {
+        if let Some(name) = format {
+            return OutputFormat::from_name(name).ok_or_else(|| {
+                ApiError::bad_request(&format!("{} is not an output format", name))
+            });
+        }
+        Ok(request
+            .accept()
+            .and_then(|accept| accept.media_types().find_map(OutputFormat::from_media_type))
+            .unwrap_or(OutputFormat::Csv))
+    }
+    pub fn content_type(self) -> ContentType {
+        match self {
+            OutputFormat::Csv => ContentType::new("text", "csv"),
+            OutputFormat::Parquet => ContentType::new("application", "vnd.apache.parquet"),
+            OutputFormat::Arrow => ContentType::new("application", "vnd.apache.arrow.stream"),
+            OutputFormat::Xlsx => ContentType::new(
+                "application",
+                "vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            ),
+        }
+    }
+    /// `query` is echoed in the formats that have room for it. `currency` are the metrics
+    /// formatted as amounts of money in xlsx.
+    pub fn write(
+        self,
+        rows: Vec<OutputDataRow>,
+        query: &QuasrQuery,
+        currency: &HashSet<String>,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            OutputFormat::Csv => Ok(qs_rows_to_string(rows).into_bytes()),
+            OutputFormat::Parquet => qs_rows_to_parquet(&rows, query).map_err(|e| e.to_string()),
+            OutputFormat::Arrow => qs_rows_to_arrow_stream(&rows, query).map_err(|e| e.to_string()),
+            OutputFormat::Xlsx => {
+                qs_rows_to_xlsx(&rows, query, currency).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{
+    answer_query,
+    auth::ApiKey,
+    check_metric_names, check_span,
+    error::ApiError,
+    known_metric_names,
+    logging::{query_span, RequestId},
+    parse_date,
+    rate_limit::Throttle,
+    settings::Settings,
+    sql_for,
+    telemetry::Telemetry,
+    DbConn,
+};
+use quasr_core::{
+    build_budgeted_nodes_sql, build_node_details_sql,
+    calendar::CoreCalendar,
+    input::{CoreMarketingNodeLevel, CoreTimeBreakdown, QuasrQuery},
+    output_marketing_nodes,
+    pacing::{pace_nodes, PacingPeriod},
+    CoreMetric, MarketingNode,
+};
+use quasr_io::data_input::mysql::load_node_details_from_db;
+use rocket::{get, State};
+use rocket_contrib::json::Json;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+const DEFAULT_SPEND_METRIC: &str = "Cost";
+
+fn spend_query(
+    org_id: &str,
+    level: CoreMarketingNodeLevel,
+    spend_metric: String,
+    period: &PacingPeriod,
+    calendar: CoreCalendar,
+) -> QuasrQuery {
+    QuasrQuery {
+        metrics: vec![CoreMetric::UpperFunnelMetric(spend_metric)],
+        org_id: org_id.to_owned(),
+        start_date: period.start_date,
+        end_date: period.as_of,
+        marketing_node_breakdown: Some(level),
+        marketing_node_filter: None,
+        ad_platform_breakdown: false,
+        time_breakdown: None,
+        property_attribute_breakdown: vec![],
+        property_attribute_filter: vec![],
+        include_metadata: false,
+        calendar,
+    }
+}
+/// Spend of the organisation's campaigns or ad sets against their daily budgets. The period
+/// defaults to the organisation's current month, and spend is counted up to yesterday.
+#[get("/orgs/<org_id>/pacing?<level>&<start_date>&<end_date>&<tolerance>&<spend_metric>")]
+pub fn pacing(
+    org_id: String,
+    level: Option<String>,
+    start_date: Option<String>,
+    end_date: Option<String>,
+    tolerance: Option<f64>,
+    spend_metric: Option<String>,
+    key: ApiKey,
+    _throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+    telemetry: State<Telemetry>,
+    request_id: RequestId,
+) -> Result<Json<Value>, ApiError> {
+    key.authorize(&org_id)?;
+    let level = match level.as_deref().unwrap_or("campaign") {
+        "campaign" => CoreMarketingNodeLevel::Campaign,
+        "adSet" => CoreMarketingNodeLevel::AdSet,
+        other => {
+            return Err(ApiError::bad_request(&format!(
+                "Only campaigns and ad sets have budgets, not {}",
+                other
+            )))
+        }
+    };
+    let org = settings.org(&org_id);
+    let tolerance = tolerance.unwrap_or(org.pacing_tolerance);
+    if !tolerance.is_finite() || tolerance < 0.0 {
+        return Err(ApiError::bad_request(
+            "The tolerance has to be a number that isn't negative",
+        ));
+    }
+    let today = settings.today(&org_id);
+    let (month_start, month_end) = org.calendar.bucket(today, CoreTimeBreakdown::Month);
+    let start_date = parse_date(start_date)?.unwrap_or(month_start);
+    let end_date = parse_date(end_date)?.unwrap_or(month_end);
+    if end_date < start_date {
+        return Err(ApiError::bad_request("The period ends before it starts"));
+    }
+    // Today's spend is still coming in
+    let period = PacingPeriod::new(start_date, end_date, today.pred())
+        .ok_or_else(|| ApiError::bad_request("The period has no finished days yet"))?;
+    let q = spend_query(
+        &org_id,
+        level,
+        spend_metric.unwrap_or_else(|| DEFAULT_SPEND_METRIC.to_owned()),
+        &period,
+        org.calendar.clone(),
+    );
+    check_span(&q, &settings)?;
+    let _span = query_span(&request_id, &q).entered();
+    check_metric_names(
+        &q,
+        &known_metric_names(&conn, &org_id, &settings.schema)?,
+        false,
+    )?;
+    let sql_query = sql_for(&q, &settings.schema);
+    let spend = answer_query(
+        &conn,
+        q,
+        sql_query,
+        &settings.schema,
+        &org.limits,
+        &telemetry,
+    )?;
+    // Nodes with a budget are paced even if they haven't spent anything
+    let mut details = load_node_details_from_db(
+        &conn,
+        build_budgeted_nodes_sql(&org_id, level, &settings.schema),
+    )?;
+    let nodes: HashSet<MarketingNode> = output_marketing_nodes(&spend)
+        .into_iter()
+        .filter(|node| !details.contains_key(node))
+        .collect();
+    if !nodes.is_empty() {
+        details.extend(load_node_details_from_db(
+            &conn,
+            build_node_details_sql(&org_id, &nodes, &settings.schema),
+        )?);
+    }
+    Ok(Json(json!({
+        "period": period,
+        "tolerance": tolerance,
+        "nodes": pace_nodes(&spend, &details, &period, tolerance),
+    })))
+}
@@ -0,0 +1,237 @@
+use crate::{input::PropertyAttributes, MarketingNode, OutputDataRow};
+use chrono::{Duration, NaiveDate};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+type SeriesKey = (
+    usize,
+    Option<MarketingNode>,
+    Option<String>,
+    PropertyAttributes,
+);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CoreAnomalyMethod {
+    /// Distance from the baseline's mean, in standard deviations
+    ZScore,
+    /// Distance from the baseline's median, in median absolute deviations scaled to be
+    /// comparable to standard deviations. Less thrown off by earlier anomalies.
+    MedianAbsoluteDeviation,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct CoreAnomalySettings {
+    pub method: CoreAnomalyMethod,
+    /// Days before each day its value is compared against
+    pub baseline_days: u32,
+    /// Scores from which a day is flagged
+    pub threshold: f64,
+    /// Days with fewer values in their baseline are never flagged
+    pub min_observations: usize,
+}
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    Warning,
+    /// The score is at least twice the threshold
+    Critical,
+}
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Anomaly {
+    pub metric_index: usize,
+    pub marketing_node: Option<MarketingNode>,
+    pub ad_platform: Option<String>,
+    pub property_attributes: PropertyAttributes,
+    pub date: NaiveDate,
+    pub value: f64,
+    /// Centre of the baseline: its mean or median
+    pub expected: f64,
+    pub deviation: f64,
+    /// Deviation in standard deviations, or scaled median absolute deviations
+    pub score: f64,
+    pub severity: Severity,
+}
+/// Makes the median absolute deviation of normally distributed values match their standard
+/// deviation
+const MAD_SCALE: f64 = 1.4826;
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if mid * 2 == values.len() {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+/// Expected value and spread of the baseline
+fn centre_and_spread(method: CoreAnomalyMethod, baseline: &mut [f64]) -> (f64, f64) {
+    match method {
+        CoreAnomalyMethod::ZScore => {
+            let n = baseline.len() as f64;
+            let mean = baseline.iter().sum::<f64>() / n;
+            let variance = baseline.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+            (mean, variance.sqrt())
+        }
+        CoreAnomalyMethod::MedianAbsoluteDeviation => {
+            let centre = median(baseline);
+            let mut deviations: Vec<f64> = baseline.iter().map(|v| (v - centre).abs()).collect();
+            (centre, median(&mut deviations) * MAD_SCALE)
+        }
+    }
+}
+/// Flags the days from `start_date` on whose value is far off the values of the days before.
+/// `rows` have to be broken down by day, they are split into one series per metric, node, ad
+/// platform and property attributes. Days without a row are missing, not zero, so sparse
+/// series aren't flagged for their gaps, and days whose baseline doesn't vary can't be scored.
+pub fn find_anomalies(
+    rows: &[OutputDataRow],
+    settings: &CoreAnomalySettings,
+    start_date: NaiveDate,
+) -> Vec<Anomaly> {
+    let mut series: HashMap<SeriesKey, BTreeMap<NaiveDate, f64>> = HashMap::new();
+    for r in rows {
+        let key = (
+            r.metric_index,
+            r.marketing_node.clone(),
+            r.ad_platform.clone(),
+            r.property_attributes.clone(),
+        );
+        *series
+            .entry(key)
+            .or_default()
+            .entry(r.start_date)
+            .or_insert(0.0) += r.value;
+    }
+    let mut ret = vec![];
+    for ((metric_index, marketing_node, ad_platform, property_attributes), values) in series {
+        for (date, value) in values.range(start_date..) {
+            let before =
+                match date.checked_sub_signed(Duration::days(settings.baseline_days.into())) {
+                    Some(baseline_start) => values.range(baseline_start..*date),
+                    // The baseline starts before the earliest date there can be
+                    None => values.range(..*date),
+                };
+            let mut baseline: Vec<f64> = before.map(|v| *v.1).collect();
+            if baseline.len() < settings.min_observations.max(1) {
+                continue;
+            }
+            let (expected, spread) = centre_and_spread(settings.method, &mut baseline);
+            if spread == 0.0 {
+                continue;
+            }
+            let deviation = value - expected;
+            let score = deviation / spread;
+            if score.abs() < settings.threshold {
+                continue;
+            }
+            ret.push(Anomaly {
+                metric_index,
+                marketing_node: marketing_node.clone(),
+                ad_platform: ad_platform.clone(),
+                property_attributes: property_attributes.clone(),
+                date: *date,
+                value: *value,
+                expected,
+                deviation,
+                score,
+                severity: if score.abs() >= 2.0 * settings.threshold {
+                    Severity::Critical
+                } else {
+                    Severity::Warning
+                },
+            });
+        }
+    }
+    ret.sort_by(|a, b| {
+        a.date
+            .cmp(&b.date)
+            .then(a.metric_index.cmp(&b.metric_index))
+            .then_with(|| a.marketing_node.cmp(&b.marketing_node))
+    });
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_anomalies, CoreAnomalyMethod, CoreAnomalySettings, Severity};
+    use crate::{input::PropertyAttributes, OutputDataRow};
+    use chrono::{Duration, NaiveDate};
+
+    fn series(node: &str, values: &[Option<f64>]) -> Vec<OutputDataRow> {
+        let start = NaiveDate::from_ymd(2020, 6, 1);
+        values
+            .iter()
+            .enumerate()
+            .filter_map(|(day, value)| {
+                let date = start + Duration::days(day as i64);
+                value.map(|value| OutputDataRow {
+                    value,
+                    start_date: date,
+                    end_date: date,
+                    metric_index: 0,
+                    marketing_node: Some(node.to_owned()),
+                    ad_platform: None,
+                    property_attributes: PropertyAttributes::default(),
+                    node_details: None,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_anomalies() {
+        let mut rows = series(
+            "steady",
+            &[
+                Some(2.0),
+                Some(2.2),
+                Some(1.8),
+                Some(2.1),
+                Some(1.9),
+                Some(2.0),
+                Some(4.1),
+            ],
+        );
+        // Only two days with data before the spike
+        rows.extend(series(
+            "sparse",
+            &[Some(2.0), None, None, None, None, Some(2.1), Some(9.0)],
+        ));
+        let mut settings = CoreAnomalySettings {
+            method: CoreAnomalyMethod::ZScore,
+            baseline_days: 6,
+            threshold: 3.0,
+            min_observations: 3,
+        };
+        let anomalies = find_anomalies(&rows, &settings, NaiveDate::from_ymd(2020, 6, 7));
+        assert_eq!(anomalies.len(), 1);
+        let anomaly = &anomalies[0];
+        assert_eq!(anomaly.marketing_node.as_deref(), Some("steady"));
+        assert_eq!(anomaly.date, NaiveDate::from_ymd(2020, 6, 7));
+        assert!((anomaly.expected - 2.0).abs() < 1e-9);
+        assert!((anomaly.deviation - 2.1).abs() < 1e-9);
+        assert_eq!(anomaly.severity, Severity::Critical);
+
+        settings.method = CoreAnomalyMethod::MedianAbsoluteDeviation;
+        settings.min_observations = 2;
+        let anomalies = find_anomalies(&rows, &settings, NaiveDate::from_ymd(2020, 6, 7));
+        assert_eq!(anomalies.len(), 2);
+        assert!(anomalies
+            .iter()
+            .all(|a| a.severity == Severity::Critical && a.deviation > 0.0));
+    }
+    #[test]
+    fn baselines_out_of_range_or_with_nan_dont_panic() {
+        let rows = series("odd", &[Some(2.0), Some(f64::NAN), Some(1.0), Some(9.0)]);
+        let settings = CoreAnomalySettings {
+            method: CoreAnomalyMethod::MedianAbsoluteDeviation,
+            baseline_days: u32::MAX,
+            threshold: 3.0,
+            min_observations: 3,
+        };
+        let anomalies = find_anomalies(&rows, &settings, NaiveDate::from_ymd(2020, 6, 4));
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].date, NaiveDate::from_ymd(2020, 6, 4));
+    }
+}
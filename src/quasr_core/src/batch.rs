@@ -0,0 +1,68 @@
+use crate::input::QuasrQuery;
+
+/// Queries that can be answered from the rows of a single database scan
+pub struct QueryBatch {
+    /// Fetches the metrics of all the queries in the batch
+    pub scan: QuasrQuery,
+    /// The queries, with their position in the original list
+    pub queries: Vec<(usize, QuasrQuery)>,
+}
+/// Groups queries that only differ in their metrics
+pub fn batch_queries(queries: Vec<QuasrQuery>) -> Vec<QueryBatch> {
+    let mut batches: Vec<QueryBatch> = vec![];
+    for (idx, query) in queries.into_iter().enumerate() {
+        match batches.iter_mut().find(|b| b.scan.shares_scan_with(&query)) {
+            Some(batch) => {
+                batch.scan.metrics.extend(query.metrics.iter().cloned());
+                batch.queries.push((idx, query));
+            }
+            None => batches.push(QueryBatch {
+                scan: query.clone(),
+                queries: vec![(idx, query)],
+            }),
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::batch_queries;
+    use crate::{
+        calendar::CoreCalendar,
+        input::{CoreMarketingNodeLevel, QuasrQuery},
+        CoreMetric,
+    };
+    use chrono::NaiveDate;
+    fn query(metric: &str, breakdown: Option<CoreMarketingNodeLevel>) -> QuasrQuery {
+        QuasrQuery {
+            metrics: vec![CoreMetric::UpperFunnelMetric(metric.to_owned())],
+            org_id: "org".to_string(),
+            start_date: NaiveDate::from_ymd(2020, 1, 1),
+            end_date: NaiveDate::from_ymd(2020, 1, 31),
+            marketing_node_breakdown: breakdown,
+            marketing_node_filter: None,
+            ad_platform_breakdown: false,
+            time_breakdown: None,
+            property_attribute_breakdown: vec![],
+            property_attribute_filter: vec![],
+            include_metadata: false,
+            calendar: CoreCalendar::default(),
+        }
+    }
+    #[test]
+    fn test_batch_queries() {
+        let batches = batch_queries(vec![
+            query("Cost", None),
+            query("Cost", Some(CoreMarketingNodeLevel::Campaign)),
+            query("Install", None),
+        ]);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            batches[0].queries.iter().map(|q| q.0).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(batches[0].scan.metrics.len(), 2);
+        assert_eq!(batches[1].queries[0].0, 1);
+    }
+}
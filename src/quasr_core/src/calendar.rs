@@ -0,0 +1,175 @@
+use crate::input::CoreTimeBreakdown;
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use serde::Serialize;
+
+/// A fiscal year of 52 or 53 weeks, split into quarters of three periods, e.g. 4-4-5 weeks.
+/// The 53rd week, when there is one, belongs to the last period.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoreFiscalCalendar {
+    /// First day of any fiscal year. Every year starts on the same weekday, the one nearest to
+    /// the same day of the year.
+    pub year_start: NaiveDate,
+    pub weeks_per_period: [i64; 3],
+}
+/// How an organisation's days are grouped into weeks and months
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoreCalendar {
+    pub week_start: Weekday,
+    /// Months are fiscal periods instead of calendar months when set
+    pub fiscal: Option<CoreFiscalCalendar>,
+}
+impl Default for CoreCalendar {
+    fn default() -> Self {
+        CoreCalendar {
+            week_start: Weekday::Mon,
+            fiscal: None,
+        }
+    }
+}
+impl CoreFiscalCalendar {
+    /// First day of the fiscal year that starts around `year`'s anniversary of `year_start`
+    fn start_of_year(&self, year: i32) -> NaiveDate {
+        let (month, day) = (self.year_start.month(), self.year_start.day());
+        // A year start on February 29th is kept on the 28th in other years
+        let anniversary = NaiveDate::from_ymd_opt(year, month, day)
+            .or_else(|| NaiveDate::from_ymd_opt(year, month, day - 1))
+            .unwrap();
+        let days_since_weekday = (anniversary.weekday().num_days_from_monday() + 7
+            - self.year_start.weekday().num_days_from_monday())
+            % 7;
+        if days_since_weekday <= 3 {
+            anniversary - Duration::days(days_since_weekday.into())
+        } else {
+            anniversary + Duration::days((7 - days_since_weekday).into())
+        }
+    }
+    fn period(&self, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let year = (date.year() - 2..=date.year() + 1)
+            .rev()
+            .find(|year| self.start_of_year(*year) <= date)
+            .unwrap();
+        let year_start = self.start_of_year(year);
+        let year_end = self.start_of_year(year + 1) - Duration::days(1);
+        let week = (date - year_start).num_days() / 7;
+        let mut period_start = 0;
+        for (i, weeks) in self.weeks_per_period.iter().cycle().take(12).enumerate() {
+            if week < period_start + weeks || i == 11 {
+                let period_end = if i == 11 {
+                    year_end
+                } else {
+                    year_start + Duration::weeks(period_start + weeks) - Duration::days(1)
+                };
+                return (year_start + Duration::weeks(period_start), period_end);
+            }
+            period_start += weeks;
+        }
+        unreachable!()
+    }
+}
+impl CoreCalendar {
+    /// First and last day of the bucket the date falls in
+    pub fn bucket(&self, date: NaiveDate, breakdown: CoreTimeBreakdown) -> (NaiveDate, NaiveDate) {
+        match breakdown {
+            CoreTimeBreakdown::Day => (date, date),
+            CoreTimeBreakdown::Week => {
+                let days_into_week = (date.weekday().num_days_from_monday() + 7
+                    - self.week_start.num_days_from_monday())
+                    % 7;
+                let start = date - Duration::days(days_into_week.into());
+                (start, start + Duration::days(6))
+            }
+            CoreTimeBreakdown::Month => match &self.fiscal {
+                Some(fiscal) => fiscal.period(date),
+                None => {
+                    let start = date.with_day(1).unwrap();
+                    // December ends on the 31st, other months the day before the next one starts
+                    let end = if start.month() == 12 {
+                        start.with_day(31)
+                    } else {
+                        start
+                            .with_month(start.month() + 1)
+                            .and_then(|next_month| next_month.pred_opt())
+                    };
+                    (start, end.unwrap())
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoreCalendar, CoreFiscalCalendar};
+    use crate::input::CoreTimeBreakdown;
+    use chrono::{NaiveDate, Weekday};
+
+    #[test]
+    fn test_weeks() {
+        let d = NaiveDate::from_ymd;
+        // 2020-06-03 is a Wednesday
+        let monday = CoreCalendar::default();
+        assert_eq!(
+            monday.bucket(d(2020, 6, 3), CoreTimeBreakdown::Week),
+            (d(2020, 6, 1), d(2020, 6, 7))
+        );
+        let sunday = CoreCalendar {
+            week_start: Weekday::Sun,
+            fiscal: None,
+        };
+        assert_eq!(
+            sunday.bucket(d(2020, 6, 3), CoreTimeBreakdown::Week),
+            (d(2020, 5, 31), d(2020, 6, 6))
+        );
+        assert_eq!(
+            sunday.bucket(d(2020, 5, 31), CoreTimeBreakdown::Week),
+            (d(2020, 5, 31), d(2020, 6, 6))
+        );
+    }
+    #[test]
+    fn test_months() {
+        let d = NaiveDate::from_ymd;
+        assert_eq!(
+            CoreCalendar::default().bucket(d(2020, 12, 24), CoreTimeBreakdown::Month),
+            (d(2020, 12, 1), d(2020, 12, 31))
+        );
+        let fiscal = CoreCalendar {
+            week_start: Weekday::Sun,
+            fiscal: Some(CoreFiscalCalendar {
+                year_start: d(2020, 2, 2),
+                weeks_per_period: [4, 4, 5],
+            }),
+        };
+        // First period
+        assert_eq!(
+            fiscal.bucket(d(2020, 2, 2), CoreTimeBreakdown::Month),
+            (d(2020, 2, 2), d(2020, 2, 29))
+        );
+        // Third, five week, period
+        assert_eq!(
+            fiscal.bucket(d(2020, 4, 30), CoreTimeBreakdown::Month),
+            (d(2020, 3, 29), d(2020, 5, 2))
+        );
+        // Last period of the previous fiscal year
+        assert_eq!(
+            fiscal.bucket(d(2020, 2, 1), CoreTimeBreakdown::Month),
+            (d(2019, 12, 29), d(2020, 2, 1))
+        );
+        // Fiscal 2022 starts on 2022-01-30 and has 53 weeks, as fiscal 2023 starts on the Sunday
+        // nearest to February 2nd, 2023-02-05. The extra week is in its last period.
+        assert_eq!(
+            fiscal.bucket(d(2023, 2, 4), CoreTimeBreakdown::Month),
+            (d(2022, 12, 25), d(2023, 2, 4))
+        );
+        assert_eq!(
+            fiscal.bucket(d(2023, 2, 5), CoreTimeBreakdown::Month),
+            (d(2023, 2, 5), d(2023, 3, 4))
+        );
+        // Six years later the periods still start on the Sunday nearest to February 2nd
+        assert_eq!(
+            fiscal.bucket(d(2026, 2, 1), CoreTimeBreakdown::Month),
+            (d(2026, 2, 1), d(2026, 2, 28))
+        );
+    }
+}
@@ -1,38 +1,237 @@
-use crate::{MarketingNode, MetricName};
-use chrono::NaiveDate;
-use std::collections::HashSet;
+use crate::{calendar::CoreCalendar, MarketingNode, MetricName};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeSet, HashMap, HashSet};
 pub type InputDataVec = Vec<InputDataRow>;
-#[derive(Debug)]
+/// Serializes the names in order, so the same metric always serializes the same
+fn sorted<S: Serializer>(names: &HashSet<MetricName>, serializer: S) -> Result<S::Ok, S::Error> {
+    names.iter().collect::<BTreeSet<_>>().serialize(serializer)
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct CoreMarketingNodeFilter {
     pub value: Vec<MarketingNode>,
     pub level: CoreMarketingNodeLevel,
 }
-#[derive(Debug, Eq, PartialEq)]
-
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum CoreMetric {
     UpperFunnelMetric(MetricName),
-    SummationMetric(HashSet<MetricName>),
+    SummationMetric(#[serde(serialize_with = "sorted")] HashSet<MetricName>),
     DivisionMetric {
+        #[serde(serialize_with = "sorted")]
         numerator: HashSet<MetricName>,
+        #[serde(serialize_with = "sorted")]
         denominator: HashSet<MetricName>,
     },
+    /// Only counts lower funnel values with the given attribution
+    AttributedMetric {
+        attribution: CoreAttribution,
+        metric: Box<CoreMetric>,
+    },
+    /// Applies the transform to the daily values of each marketing node and ad platform
+    TransformedMetric {
+        transform: CoreTransform,
+        metric: Box<CoreMetric>,
+    },
+}
+/// Longest rolling window, in days
+pub const MAX_ROLLING_WINDOW: u32 = 366;
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CoreTransform {
+    /// Sum of the last `window` days, including the current one
+    RollingSum { window: u32 },
+    /// Average of the last `window` days, days without values count as zero
+    RollingAverage { window: u32 },
+    /// Sum of all the days since the start date
+    Cumulative,
+}
+impl CoreTransform {
+    /// Days before the start date needed to compute the first day
+    pub fn lookback_days(&self) -> i64 {
+        match self {
+            Self::RollingSum { window } | Self::RollingAverage { window } => i64::from(*window) - 1,
+            Self::Cumulative => 0,
+        }
+    }
+}
+/// Attribution settings of a lower funnel metric, e.g. 7 day click
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct CoreAttribution {
+    pub mode: String,
+    pub window: i32,
 }
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum CoreMarketingNodeLevel {
     Campaign,
     Ad,
     AdSet,
 }
-impl CoreMarketingNodeLevel {
-    pub fn to_database_column_id_string(&self) -> &str {
+impl CoreMetric {
+    /// Names of the metrics, as stored in `UpperFunnelMetricFields`, that this metric is built from
+    pub fn base_metric_names(&self) -> HashSet<MetricName> {
+        match self {
+            CoreMetric::UpperFunnelMetric(metric_name) => {
+                std::iter::once(metric_name.clone()).collect()
+            }
+            CoreMetric::SummationMetric(metrics) => metrics.clone(),
+            CoreMetric::DivisionMetric {
+                denominator,
+                numerator,
+            } => denominator.union(numerator).cloned().collect(),
+            CoreMetric::AttributedMetric { metric, .. }
+            | CoreMetric::TransformedMetric { metric, .. } => metric.base_metric_names(),
+        }
+    }
+    pub fn attribution(&self) -> Option<&CoreAttribution> {
+        match self {
+            CoreMetric::AttributedMetric { attribution, .. } => Some(attribution),
+            CoreMetric::TransformedMetric { metric, .. } => metric.attribution(),
+            _ => None,
+        }
+    }
+    pub fn lookback_days(&self) -> i64 {
+        match self {
+            CoreMetric::AttributedMetric { metric, .. } => metric.lookback_days(),
+            CoreMetric::TransformedMetric { transform, metric } => {
+                transform.lookback_days() + metric.lookback_days()
+            }
+            _ => 0,
+        }
+    }
+    /// Readable name of the metric, e.g. `Cost / Clicks` or `Purchases (7 day click)`
+    pub fn label(&self) -> String {
+        fn sum(names: &HashSet<MetricName>) -> String {
+            let mut names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+            names.sort_unstable();
+            names.join(" + ")
+        }
+        fn operand(names: &HashSet<MetricName>) -> String {
+            if names.len() > 1 {
+                format!("({})", sum(names))
+            } else {
+                sum(names)
+            }
+        }
+        match self {
+            CoreMetric::UpperFunnelMetric(name) => name.clone(),
+            CoreMetric::SummationMetric(names) => sum(names),
+            CoreMetric::DivisionMetric {
+                numerator,
+                denominator,
+            } => format!("{} / {}", operand(numerator), operand(denominator)),
+            CoreMetric::AttributedMetric {
+                attribution,
+                metric,
+            } => format!(
+                "{} ({} day {})",
+                metric.label(),
+                attribution.window,
+                attribution.mode
+            ),
+            CoreMetric::TransformedMetric { transform, metric } => match transform {
+                CoreTransform::RollingSum { window } => {
+                    format!("{} ({} day rolling sum)", metric.label(), window)
+                }
+                CoreTransform::RollingAverage { window } => {
+                    format!("{} ({} day rolling average)", metric.label(), window)
+                }
+                CoreTransform::Cumulative => format!("{} (cumulative)", metric.label()),
+            },
+        }
+    }
+    /// Whether the metric is a ratio of other metrics
+    pub fn is_division(&self) -> bool {
         match self {
-            Self::Campaign => "campaignId",
-            Self::Ad => "adId",
-            Self::AdSet => "adSetId",
+            CoreMetric::DivisionMetric { .. } => true,
+            CoreMetric::AttributedMetric { metric, .. }
+            | CoreMetric::TransformedMetric { metric, .. } => metric.is_division(),
+            _ => false,
         }
     }
 }
-#[derive(Debug)]
+/// Attributes of a marketing node that live on the `Properties` table
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CorePropertyAttribute {
+    Objective,
+    PropertyStatus,
+    DisplayStatus,
+    BidStrategy,
+    PropertyType,
+}
+impl CorePropertyAttribute {
+    pub fn to_database_column_string(&self) -> &str {
+        match self {
+            Self::Objective => "objective",
+            Self::PropertyStatus => "propertyStatus",
+            Self::DisplayStatus => "displayStatus",
+            Self::BidStrategy => "bidStrategy",
+            Self::PropertyType => "propertyType",
+        }
+    }
+    /// Name the attribute is selected as in the generated SQL
+    pub fn to_alias_string(&self) -> &str {
+        match self {
+            Self::Objective => "objective",
+            Self::PropertyStatus => "property_status",
+            Self::DisplayStatus => "display_status",
+            Self::BidStrategy => "bid_strategy",
+            Self::PropertyType => "property_type",
+        }
+    }
+    pub fn all() -> [CorePropertyAttribute; 5] {
+        [
+            Self::Objective,
+            Self::PropertyStatus,
+            Self::DisplayStatus,
+            Self::BidStrategy,
+            Self::PropertyType,
+        ]
+    }
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct CorePropertyAttributeFilter {
+    pub attribute: CorePropertyAttribute,
+    pub value: Vec<String>,
+}
+/// Values of the property attributes a row was broken down by.
+/// Attributes that are not part of the breakdown are `None`
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PropertyAttributes {
+    pub objective: Option<String>,
+    pub property_status: Option<String>,
+    pub display_status: Option<String>,
+    pub bid_strategy: Option<String>,
+    pub property_type: Option<String>,
+}
+impl PropertyAttributes {
+    pub fn get(&self, attribute: CorePropertyAttribute) -> Option<&String> {
+        match attribute {
+            CorePropertyAttribute::Objective => self.objective.as_ref(),
+            CorePropertyAttribute::PropertyStatus => self.property_status.as_ref(),
+            CorePropertyAttribute::DisplayStatus => self.display_status.as_ref(),
+            CorePropertyAttribute::BidStrategy => self.bid_strategy.as_ref(),
+            CorePropertyAttribute::PropertyType => self.property_type.as_ref(),
+        }
+    }
+}
+/// Details of a marketing node, taken from its own row in `Properties`
+#[derive(Debug, Clone)]
+pub struct MarketingNodeDetails {
+    pub name: String,
+    pub handle: Option<String>,
+    pub property_status: Option<String>,
+    pub external_created_at: Option<NaiveDateTime>,
+    pub campaign_id: Option<String>,
+    pub ad_set_id: Option<String>,
+    /// Only set for nodes with their own budget, usually campaigns or ad sets
+    pub daily_budget: Option<f64>,
+}
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 /// This is the type that the system receives
 pub struct InputDataRow {
     pub value: f64,
@@ -40,6 +239,11 @@ pub struct InputDataRow {
     pub metric_name: MetricName,
     pub marketing_node: Option<MarketingNode>,
     pub ad_platform: String,
+    #[serde(default)]
+    pub property_attributes: PropertyAttributes,
+    /// Only set when the query has attributed metrics
+    #[serde(default)]
+    pub attribution: Option<CoreAttribution>,
 }
 impl InputDataRow {
     pub fn mock() -> InputDataVec {
@@ -49,14 +253,20 @@ impl InputDataRow {
             marketing_node: Some("mnode1".to_string()),
             value: 140.0,
             ad_platform: "mock".to_string(),
+            property_attributes: PropertyAttributes::default(),
+            attribution: None,
         }]
     }
 }
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum CoreTimeBreakdown {
     Day,
+    Week,
+    Month,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct QuasrQuery {
     pub metrics: Vec<CoreMetric>,
     pub org_id: String,
@@ -66,4 +276,61 @@ pub struct QuasrQuery {
     pub marketing_node_filter: Option<CoreMarketingNodeFilter>,
     pub ad_platform_breakdown: bool,
     pub time_breakdown: Option<CoreTimeBreakdown>,
+    pub property_attribute_breakdown: Vec<CorePropertyAttribute>,
+    pub property_attribute_filter: Vec<CorePropertyAttributeFilter>,
+    pub include_metadata: bool,
+    /// How days are grouped by weekly and monthly breakdowns
+    pub calendar: CoreCalendar,
+}
+impl QuasrQuery {
+    /// Names of all the metrics that have to be fetched to answer the query
+    pub fn base_metric_names(&self) -> HashSet<MetricName> {
+        self.metrics
+            .iter()
+            .flat_map(|m| m.base_metric_names())
+            .collect()
+    }
+    /// Whether both queries fetch the same rows, except for the metrics
+    pub fn shares_scan_with(&self, other: &QuasrQuery) -> bool {
+        self.org_id == other.org_id
+            && self.start_date == other.start_date
+            && self.end_date == other.end_date
+            && self.marketing_node_breakdown == other.marketing_node_breakdown
+            && self.marketing_node_filter == other.marketing_node_filter
+            && self.ad_platform_breakdown == other.ad_platform_breakdown
+            && self.time_breakdown == other.time_breakdown
+            && self.property_attribute_breakdown == other.property_attribute_breakdown
+            && self.property_attribute_filter == other.property_attribute_filter
+            && self.lookback_days() == other.lookback_days()
+    }
+    /// Days before the start date that transformed metrics need
+    pub fn lookback_days(&self) -> i64 {
+        self.metrics
+            .iter()
+            .map(|m| m.lookback_days())
+            .max()
+            .unwrap_or(0)
+    }
+    /// First day the database has to be queried for
+    pub fn fetch_start_date(&self) -> NaiveDate {
+        // Only dates at the very start of the calendar have no room for the lookback
+        self.start_date
+            .checked_sub_signed(Duration::days(self.lookback_days()))
+            .unwrap_or(self.start_date)
+    }
+    pub fn has_attributed_metrics(&self) -> bool {
+        self.metrics.iter().any(|m| m.attribution().is_some())
+    }
+    /// Base metric names grouped by the attribution they are requested with
+    pub fn base_metric_names_by_attribution(
+        &self,
+    ) -> HashMap<Option<CoreAttribution>, HashSet<MetricName>> {
+        let mut ret: HashMap<Option<CoreAttribution>, HashSet<MetricName>> = HashMap::new();
+        self.metrics.iter().for_each(|m| {
+            ret.entry(m.attribution().cloned())
+                .or_default()
+                .extend(m.base_metric_names())
+        });
+        ret
+    }
 }
@@ -1,28 +1,64 @@
 use crate::metric_processing::{
-    get_division_metric_from_metrics, get_filter_date_or_self_date,
-    get_summation_metric_from_metrics,
+    bucket_dates, get_division_metric_from_metrics, get_filter_date_or_self_date,
+    get_summation_metric_from_metrics, merge_attributions,
 };
 use chrono::NaiveDate;
-use input::QuasrQuery;
+use input::{
+    CoreAttribution, CoreMarketingNodeLevel, CoreTimeBreakdown, CoreTransform,
+    MarketingNodeDetails, PropertyAttributes, QuasrQuery,
+};
+use schema::{PropertiesTable, SchemaMapping};
+use std::collections::{HashMap, HashSet};
+use transforms::{transform_ratio, transform_rows};
 
+pub mod anomalies;
+pub mod batch;
+pub mod calendar;
 pub mod input;
+pub mod limits;
 pub mod macros;
 mod metric_processing;
+pub mod pacing;
 mod processors;
+pub mod schema;
+mod transforms;
+pub mod validation;
 pub type MetricName = String;
 pub type MarketingNode = String;
 use crate::processors::{
-    BaseFilter, MarketingNodeBreakdown, MarketingNodeFilter, MetricSelector, Processor,
-    TimeBreakdown, TimeFilter,
+    AttributionBreakdown, BaseFilter, Condition, MarketingNodeBreakdown, MarketingNodeFilter,
+    MetricSelector, Processor, PropertyAttributeBreakdown, PropertyAttributeFilter, TimeBreakdown,
+    TimeFilter,
 };
 pub use input::CoreMetric;
 
-/// A string representing valid SQL code
-pub struct CoreSqlString(String);
+/// A string representing valid SQL code, with a `?` placeholder for each of its parameters
+pub struct CoreSqlString(String, Vec<String>);
 impl CoreSqlString {
     pub fn to_string(self) -> String {
         self.0
     }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    /// Values of the placeholders, in order
+    pub fn params(&self) -> &[String] {
+        &self.1
+    }
+    /// Asks MySQL to stop the query once it has run for `ms` milliseconds
+    pub fn with_max_execution_time(self, ms: u64) -> Self {
+        match self.0.strip_prefix("SELECT ") {
+            Some(rest) => CoreSqlString(
+                format!("SELECT /*+ MAX_EXECUTION_TIME({}) */ {}", ms, rest),
+                self.1,
+            ),
+            None => self,
+        }
+    }
+    /// Returns at most `rows` rows
+    pub fn with_limit(self, rows: usize) -> Self {
+        CoreSqlString(format!("{} LIMIT {}", self.0, rows), self.1)
+    }
 }
 /// This is the type that the system outputs
 #[derive(Debug)]
@@ -33,11 +69,13 @@ pub struct OutputDataRow {
     pub metric_index: usize,
     pub marketing_node: Option<MarketingNode>,
     pub ad_platform: Option<String>,
+    pub property_attributes: PropertyAttributes,
+    pub node_details: Option<MarketingNodeDetails>,
 }
 
 type OutputDataVec = Vec<OutputDataRow>;
 
-pub fn build_sql(query: &QuasrQuery) -> CoreSqlString {
+pub fn build_sql(query: &QuasrQuery, schema: &SchemaMapping) -> CoreSqlString {
     let proc: Vec<Box<dyn Processor>> = vec![
         Box::new(BaseFilter),
         Box::new(MarketingNodeBreakdown),
@@ -45,81 +83,297 @@ pub fn build_sql(query: &QuasrQuery) -> CoreSqlString {
         Box::new(TimeBreakdown),
         Box::new(MarketingNodeFilter),
         Box::new(MetricSelector),
+        Box::new(PropertyAttributeBreakdown),
+        Box::new(PropertyAttributeFilter),
+        Box::new(AttributionBreakdown),
     ];
-    let selects: Vec<String> = { proc.iter().map(|v| v.select(&query)).flatten().collect() };
-    let filters: Vec<String> = { proc.iter().map(|v| v.filter(&query)).flatten().collect() };
-    let groupbys: Vec<String> = { proc.iter().map(|v| v.groupby(&query)).flatten().collect() };
+    let selects: Vec<String> = {
+        proc.iter()
+            .map(|v| v.select(&query, schema))
+            .flatten()
+            .collect()
+    };
+    let filters: Vec<Condition> = {
+        proc.iter()
+            .map(|v| v.filter(&query, schema))
+            .flatten()
+            .collect()
+    };
+    let groupbys: Vec<String> = {
+        proc.iter()
+            .map(|v| v.groupby(&query, schema))
+            .flatten()
+            .collect()
+    };
     let sql_string = format!(
-        "SELECT {} FROM UpperFunnelMetricValues,UpperFunnelMetricFields,\
-     Properties WHERE {} GROUP BY {}",
+        "SELECT {} FROM {},{},{} WHERE {} GROUP BY {}",
         selects.join(","),
-        filters.join(" AND "),
+        schema.qualify(&schema.values.table),
+        schema.qualify(&schema.fields.table),
+        schema.qualify(&schema.properties.table),
+        filters
+            .iter()
+            .map(|f| f.sql.as_str())
+            .collect::<Vec<&str>>()
+            .join(" AND "),
         groupbys.join(",")
     );
-    CoreSqlString(sql_string)
+    let params = filters.into_iter().flat_map(|f| f.params).collect();
+    CoreSqlString(sql_string, params)
+}
+/// Columns of the node details rows, under the default names, which is what the rows are read by
+fn node_details_columns(p: &PropertiesTable) -> String {
+    [
+        (&p.id, "marketing_node"),
+        (&p.name, "name"),
+        (&p.handle, "handle"),
+        (&p.property_status, "propertyStatus"),
+        (&p.external_created_at, "externalCreatedAt"),
+        (&p.campaign_id, "campaignId"),
+        (&p.ad_set_id, "adSetId"),
+        (&p.daily_budget, "dailyBudget"),
+    ]
+    .iter()
+    .map(|(column, alias)| {
+        if column == alias {
+            column.to_string()
+        } else {
+            format!("{} AS {}", column, alias)
+        }
+    })
+    .collect::<Vec<String>>()
+    .join(",")
+}
+/// Condition on a `Properties` row that holds when a property with metrics of the organisation
+/// bound to its `?` has it as one of `child_columns`
+fn org_scope(schema: &SchemaMapping, child_columns: &[&str]) -> String {
+    let (v, f, p) = (&schema.values, &schema.fields, &schema.properties);
+    format!(
+        "EXISTS (SELECT 1 FROM {},{},{} AS child WHERE {}=? AND {}={} AND child.{}={} \
+         AND {} IN ({}))",
+        schema.qualify(&v.table),
+        schema.qualify(&f.table),
+        schema.qualify(&p.table),
+        f.col(&f.organization_id),
+        f.col(&f.id),
+        v.col(&v.field_id),
+        p.id,
+        v.col(&v.property_id),
+        p.col(&p.id),
+        child_columns
+            .iter()
+            .map(|c| format!("child.{}", c))
+            .collect::<Vec<String>>()
+            .join(",")
+    )
+}
+/// Builds the query that fetches the `Properties` row of each marketing node. Only nodes with
+/// metrics of `org_id`, themselves or through the ads under them, are returned.
+pub fn build_node_details_sql(
+    org_id: &str,
+    nodes: &HashSet<MarketingNode>,
+    schema: &SchemaMapping,
+) -> CoreSqlString {
+    let mut nodes: Vec<String> = nodes.iter().cloned().collect();
+    nodes.sort();
+    let p = &schema.properties;
+    let sql = format!(
+        "SELECT {} FROM {} WHERE {} IN ({}) AND {}",
+        node_details_columns(p),
+        schema.qualify(&p.table),
+        p.id,
+        vec!["?"; nodes.len()].join(","),
+        org_scope(schema, &[&p.id, &p.ad_set_id, &p.campaign_id])
+    );
+    nodes.push(org_id.to_owned());
+    CoreSqlString(sql, nodes)
 }
-pub fn metrics_to_indexed_metrics(query: QuasrQuery, data: input::InputDataVec) -> OutputDataVec {
+/// Builds the query that fetches the `Properties` row of the organisation's nodes at `level`
+/// that have a daily budget, whether they have spent anything or not
+pub fn build_budgeted_nodes_sql(
+    org_id: &str,
+    level: CoreMarketingNodeLevel,
+    schema: &SchemaMapping,
+) -> CoreSqlString {
+    let p = &schema.properties;
+    let sql = format!(
+        "SELECT {} FROM {} WHERE {}>0 AND {}",
+        node_details_columns(p),
+        schema.qualify(&p.table),
+        p.daily_budget,
+        org_scope(schema, &[p.node_id(level)])
+    );
+    CoreSqlString(sql, vec![org_id.to_owned()])
+}
+/// Marketing nodes the output rows are broken down by
+pub fn output_marketing_nodes(rows: &[OutputDataRow]) -> HashSet<MarketingNode> {
+    rows.iter()
+        .filter_map(|r| r.marketing_node.clone())
+        .collect()
+}
+pub fn attach_node_details(
+    rows: &mut OutputDataVec,
+    details: &HashMap<MarketingNode, MarketingNodeDetails>,
+) {
+    rows.iter_mut().for_each(|r| {
+        r.node_details = r
+            .marketing_node
+            .as_ref()
+            .and_then(|node| details.get(node))
+            .cloned()
+    });
+}
+pub fn metrics_to_indexed_metrics(query: QuasrQuery, data: &input::InputDataVec) -> OutputDataVec {
     // Takes an array of data and returns an array of indexed data
     // That is, instead of "Cost", it's metric 0.
     // For summation or division metrics, we just iterate over the array and compose them as we go
     // We map each metric to a vector of data vecs
+    let lookback = query.lookback_days();
+    // Weekly and monthly breakdowns are fetched by day
+    let bucketed;
+    let data = match query.time_breakdown {
+        Some(CoreTimeBreakdown::Week) | Some(CoreTimeBreakdown::Month) => {
+            bucketed = bucket_dates(data, &query);
+            &bucketed
+        }
+        _ => data,
+    };
+    // With attributed metrics the rows are split by attribution, which other metrics sum over
+    let unattributed = if data.iter().any(|d| d.attribution.is_some()) {
+        Some(merge_attributions(data.iter()))
+    } else {
+        None
+    };
     query
         .metrics
         .iter()
         .enumerate()
-        .map(|(idx, metric)| match metric {
-            CoreMetric::UpperFunnelMetric(metric_name) => data
-                .iter()
-                .filter_map(|d| {
-                    if &d.metric_name == metric_name {
-                        Some(OutputDataRow {
-                            metric_index: idx,
-                            start_date: get_filter_date_or_self_date(
-                                d.date,
-                                query.start_date,
-                                query.time_breakdown,
-                            ),
-                            end_date: get_filter_date_or_self_date(
-                                d.date,
-                                query.end_date,
-                                query.time_breakdown,
-                            ),
-                            marketing_node: d.marketing_node.clone(),
-                            value: d.value,
-                            ad_platform: if query.ad_platform_breakdown.clone() {
-                                Some(d.ad_platform.clone())
-                            } else {
-                                None
-                            },
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect::<OutputDataVec>(),
-            CoreMetric::SummationMetric(metrics) => {
-                get_summation_metric_from_metrics(idx, metrics, &data, &query)
-            }
-
-            CoreMetric::DivisionMetric {
-                numerator,
-                denominator,
-            } => get_division_metric_from_metrics(idx, numerator, denominator, &data, &query),
-        })
+        .map(
+            |(idx, metric)| match (metric.attribution(), &unattributed) {
+                (Some(_), _) | (_, None) => indexed_metric(idx, metric, data, &query),
+                (None, Some(unattributed)) => indexed_metric(idx, metric, unattributed, &query),
+            },
+        )
         .flatten()
+        // Days fetched for the transformed metrics' windows are not part of the result
+        .filter(|r| lookback == 0 || r.start_date >= query.start_date)
         .collect()
 }
+fn indexed_metric(
+    idx: usize,
+    metric: &CoreMetric,
+    data: &input::InputDataVec,
+    query: &QuasrQuery,
+) -> OutputDataVec {
+    match metric {
+        CoreMetric::UpperFunnelMetric(metric_name) => data
+            .iter()
+            .filter_map(|d| {
+                if &d.metric_name == metric_name {
+                    let (start_date, end_date) = get_filter_date_or_self_date(d.date, query);
+                    Some(OutputDataRow {
+                        metric_index: idx,
+                        start_date,
+                        end_date,
+                        marketing_node: d.marketing_node.clone(),
+                        value: d.value,
+                        ad_platform: if query.ad_platform_breakdown {
+                            Some(d.ad_platform.clone())
+                        } else {
+                            None
+                        },
+                        property_attributes: d.property_attributes.clone(),
+                        node_details: None,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect::<OutputDataVec>(),
+        CoreMetric::SummationMetric(metrics) => {
+            get_summation_metric_from_metrics(idx, metrics, data, query)
+        }
+
+        CoreMetric::DivisionMetric {
+            numerator,
+            denominator,
+        } => get_division_metric_from_metrics(idx, numerator, denominator, data, query),
+        CoreMetric::AttributedMetric {
+            attribution,
+            metric,
+        } => indexed_metric(idx, metric, &attributed_rows(data, attribution), query),
+        CoreMetric::TransformedMetric { transform, metric } => {
+            transformed_metric(idx, *transform, metric, data, query)
+        }
+    }
+}
+fn attributed_rows(
+    data: &input::InputDataVec,
+    attribution: &CoreAttribution,
+) -> input::InputDataVec {
+    // Values without attribution are not lower funnel, so they are always counted
+    merge_attributions(
+        data.iter()
+            .filter(|d| d.attribution.is_none() || d.attribution.as_ref() == Some(attribution)),
+    )
+}
+fn transformed_metric(
+    idx: usize,
+    transform: CoreTransform,
+    metric: &CoreMetric,
+    data: &input::InputDataVec,
+    query: &QuasrQuery,
+) -> OutputDataVec {
+    match metric {
+        CoreMetric::DivisionMetric {
+            numerator,
+            denominator,
+        } => transform_ratio(
+            idx,
+            transform,
+            get_summation_metric_from_metrics(idx, numerator, data, query),
+            get_summation_metric_from_metrics(idx, denominator, data, query),
+            query,
+        ),
+        CoreMetric::AttributedMetric {
+            attribution,
+            metric,
+        } => transformed_metric(
+            idx,
+            transform,
+            metric,
+            &attributed_rows(data, attribution),
+            query,
+        ),
+        _ => transform_rows(
+            idx,
+            transform,
+            indexed_metric(idx, metric, data, query),
+            query,
+        ),
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use super::{build_sql, get_division_metric_from_metrics, set, CoreMetric, QuasrQuery};
+    use super::{
+        attach_node_details, build_node_details_sql, build_sql, get_division_metric_from_metrics,
+        output_marketing_nodes, set, CoreMetric, QuasrQuery,
+    };
     use crate::{
-        input::{CoreMarketingNodeFilter, CoreMarketingNodeLevel, CoreTimeBreakdown, InputDataRow},
-        metrics_to_indexed_metrics, OutputDataRow,
+        calendar::CoreCalendar,
+        input::{
+            CoreAttribution, CoreMarketingNodeFilter, CoreMarketingNodeLevel,
+            CorePropertyAttribute, CorePropertyAttributeFilter, CoreTimeBreakdown, CoreTransform,
+            InputDataRow, MarketingNodeDetails, PropertyAttributes,
+        },
+        metrics_to_indexed_metrics,
+        schema::SchemaMapping,
+        OutputDataRow,
     };
-    use chrono::NaiveDate;
+    use chrono::{Datelike, NaiveDate};
     use pretty_assertions::{assert_eq, assert_ne};
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
     fn get_query() -> QuasrQuery {
         QuasrQuery {
             metrics: vec![],
@@ -130,6 +384,10 @@ mod tests {
             marketing_node_filter: None,
             ad_platform_breakdown: false,
             time_breakdown: Some(CoreTimeBreakdown::Day),
+            property_attribute_breakdown: vec![],
+            property_attribute_filter: vec![],
+            include_metadata: false,
+            calendar: CoreCalendar::default(),
         }
     }
     #[test]
@@ -154,6 +412,8 @@ mod tests {
                 marketing_node: Option::from("mnode1".to_string()),
                 date: Option::from(NaiveDate::from_ymd(2014, 7, 8)),
                 ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution: None,
             },
             // Both Numerator and denominator
             InputDataRow {
@@ -162,6 +422,8 @@ mod tests {
                 marketing_node: Option::from("mnode2".to_string()),
                 date: Option::from(NaiveDate::from_ymd(2014, 7, 8)),
                 ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution: None,
             },
             InputDataRow {
                 value: 2.0,
@@ -169,6 +431,8 @@ mod tests {
                 marketing_node: Option::from("mnode2".to_string()),
                 date: Option::from(NaiveDate::from_ymd(2014, 7, 8)),
                 ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution: None,
             },
             // No Numerator
             InputDataRow {
@@ -177,6 +441,8 @@ mod tests {
                 marketing_node: Option::from("mnode3".to_string()),
                 date: Option::from(NaiveDate::from_ymd(2015, 7, 8)),
                 ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution: None,
             },
         ];
         let ret = get_division_metric_from_metrics(
@@ -225,8 +491,12 @@ mod tests {
             }),
             ad_platform_breakdown: false,
             time_breakdown: Option::from(CoreTimeBreakdown::Day),
+            property_attribute_breakdown: vec![],
+            property_attribute_filter: vec![],
+            include_metadata: false,
+            calendar: CoreCalendar::default(),
         };
-        let res = build_sql(&input);
+        let res = build_sql(&input, &SchemaMapping::default());
         assert_eq!(
             res.0.replace("\\n"," ").replace("\n"," "),
         "SELECT SUM(sourceValue) AS sourceValue,Properties.adId AS marketing_node,UpperFunnelMetricFields.name as name,date AS qdate, \"Twitter\" as ad_platform\n\
@@ -242,6 +512,310 @@ mod tests {
         )
     }
     #[test]
+    fn test_property_attribute_breakdown_and_filter() {
+        let mut query = get_query();
+        query.property_attribute_breakdown = vec![CorePropertyAttribute::Objective];
+        query.property_attribute_filter = vec![CorePropertyAttributeFilter {
+            attribute: CorePropertyAttribute::PropertyStatus,
+            value: vec!["ACTIVE".to_owned(), "\" OR 1=1 OR \"".to_owned()],
+        }];
+        let sql = build_sql(&query, &SchemaMapping::default());
+        assert_eq!(sql.params(), ["", "ACTIVE", "\" OR 1=1 OR \""]);
+        let sql = sql.to_string();
+        assert!(sql.contains("Properties.objective AS objective"));
+        assert!(sql.contains("NULL as bid_strategy"));
+        assert!(sql.contains("Properties.propertyStatus IN (?,?)"));
+        assert!(!sql.contains("1=1"));
+        assert!(sql.ends_with(",Properties.objective"));
+    }
+    #[test]
+    fn sql_uses_the_schema_mapping() {
+        let mut schema = SchemaMapping {
+            database: Some("ads_v2".to_owned()),
+            ..SchemaMapping::default()
+        };
+        schema.values.table = "MetricValues".to_owned();
+        schema.values.date = "day".to_owned();
+        schema.properties.table = "Nodes".to_owned();
+        schema.properties.objective = "goal".to_owned();
+        schema.properties.name = "title".to_owned();
+        let mut query = get_query();
+        query.property_attribute_breakdown = vec![CorePropertyAttribute::Objective];
+        let sql = build_sql(&query, &schema).to_string();
+        assert!(
+            sql.contains("FROM ads_v2.MetricValues,ads_v2.UpperFunnelMetricFields,ads_v2.Nodes")
+        );
+        assert!(sql.contains("Nodes.id=MetricValues.propertyId"));
+        assert!(sql.contains("Nodes.goal AS objective"));
+        assert!(sql.contains("SUM(MetricValues.sourceValue)"));
+        assert!(sql.contains("MetricValues.day>="));
+        assert!(!sql.contains("Properties"));
+        let details = build_node_details_sql("test", &set!["mnode1"], &schema);
+        assert_eq!(details.params(), ["mnode1", "test"]);
+        let details = details.to_string();
+        assert!(details.starts_with("SELECT id AS marketing_node,title AS name,handle,"));
+        assert!(details.contains(
+            "FROM ads_v2.Nodes WHERE id IN (?) AND EXISTS (SELECT 1 \
+             FROM ads_v2.MetricValues,ads_v2.UpperFunnelMetricFields,ads_v2.Nodes AS child \
+             WHERE UpperFunnelMetricFields.organizationId=? "
+        ));
+        assert!(details.ends_with("AND Nodes.id IN (child.id,child.adSetId,child.campaignId))"));
+    }
+    #[test]
+    fn sql_carries_the_query_limits() {
+        let sql = build_sql(&get_query(), &SchemaMapping::default())
+            .with_max_execution_time(30000)
+            .with_limit(1001)
+            .to_string();
+        assert!(sql.starts_with("SELECT /*+ MAX_EXECUTION_TIME(30000) */ "));
+        assert!(sql.ends_with(" LIMIT 1001"));
+    }
+    #[test]
+    fn summation_metric_is_split_by_property_attributes() {
+        let mut query = get_query();
+        query.metrics = vec![CoreMetric::SummationMetric(set!["Cost"])];
+        query.property_attribute_breakdown = vec![CorePropertyAttribute::Objective];
+        let row = |objective: &str| InputDataRow {
+            value: 1.0,
+            metric_name: "Cost".to_string(),
+            marketing_node: Option::from("mnode1".to_string()),
+            date: Option::from(NaiveDate::from_ymd(2014, 7, 8)),
+            ad_platform: "mock".to_owned(),
+            property_attributes: PropertyAttributes {
+                objective: Some(objective.to_owned()),
+                ..PropertyAttributes::default()
+            },
+            attribution: None,
+        };
+        let data = vec![row("APP_INSTALLS"), row("APP_INSTALLS"), row("REACH")];
+        let mut ret = metrics_to_indexed_metrics(query, &data);
+        ret.sort_by_key(|r| r.property_attributes.objective.clone());
+        assert_eq!(
+            ret.iter()
+                .map(|r| (r.property_attributes.objective.as_deref(), r.value))
+                .collect::<Vec<_>>(),
+            vec![(Some("APP_INSTALLS"), 2.0), (Some("REACH"), 1.0)]
+        );
+    }
+    #[test]
+    fn node_details_are_attached_to_rows() {
+        let mut query = get_query();
+        query.metrics = vec![CoreMetric::UpperFunnelMetric("Cost".to_string())];
+        let data = vec![InputDataRow {
+            value: 1.0,
+            metric_name: "Cost".to_string(),
+            marketing_node: Option::from("mnode1".to_string()),
+            date: Option::from(NaiveDate::from_ymd(2014, 7, 8)),
+            ad_platform: "mock".to_owned(),
+            property_attributes: PropertyAttributes::default(),
+            attribution: None,
+        }];
+        let mut ret = metrics_to_indexed_metrics(query, &data);
+        let nodes = output_marketing_nodes(&ret);
+        assert_eq!(nodes, set!["mnode1"]);
+        assert!(
+            build_node_details_sql("test", &nodes, &SchemaMapping::default())
+                .to_string()
+                .contains("FROM Properties WHERE id IN (?) AND EXISTS ")
+        );
+        let mut details = HashMap::new();
+        details.insert(
+            "mnode1".to_owned(),
+            MarketingNodeDetails {
+                name: "Summer campaign ad".to_owned(),
+                handle: None,
+                property_status: Some("ACTIVE".to_owned()),
+                external_created_at: None,
+                campaign_id: Some("campaign1".to_owned()),
+                ad_set_id: Some("adset1".to_owned()),
+                daily_budget: None,
+            },
+        );
+        attach_node_details(&mut ret, &details);
+        assert_eq!(
+            ret[0].node_details.as_ref().map(|d| d.name.as_str()),
+            Some("Summer campaign ad")
+        );
+    }
+    #[test]
+    fn attributed_metrics_only_count_their_attribution() {
+        let click_7d = CoreAttribution {
+            mode: "click".to_owned(),
+            window: 7,
+        };
+        let mut query = get_query();
+        query.metrics = vec![
+            CoreMetric::UpperFunnelMetric("Install".to_string()),
+            CoreMetric::AttributedMetric {
+                attribution: click_7d.clone(),
+                metric: Box::new(CoreMetric::DivisionMetric {
+                    numerator: set!["Cost"],
+                    denominator: set!["Install"],
+                }),
+            },
+        ];
+        let sql = build_sql(&query, &SchemaMapping::default());
+        assert_eq!(
+            &sql.params()[1..],
+            ["Cost", "Install", "click", "7", "Install"]
+        );
+        let sql = sql.to_string();
+        assert!(sql.contains(
+            "(UpperFunnelMetricFields.name IN (?,?) AND \
+             (UpperFunnelMetricFields.attributionMode IS NULL OR \
+             (UpperFunnelMetricFields.attributionMode=? AND \
+             UpperFunnelMetricFields.attributionWindow=?)))"
+        ));
+        assert!(sql.contains("UpperFunnelMetricFields.attributionMode AS attribution_mode"));
+        let row =
+            |metric_name: &str, value: f64, attribution: Option<CoreAttribution>| InputDataRow {
+                value,
+                metric_name: metric_name.to_string(),
+                marketing_node: Option::from("mnode1".to_string()),
+                date: Option::from(NaiveDate::from_ymd(2014, 7, 8)),
+                ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution,
+            };
+        let data = vec![
+            row("Cost", 10.0, None),
+            row("Install", 4.0, Some(click_7d)),
+            row(
+                "Install",
+                1.0,
+                Some(CoreAttribution {
+                    mode: "view".to_owned(),
+                    window: 1,
+                }),
+            ),
+        ];
+        let mut ret = metrics_to_indexed_metrics(query, &data);
+        ret.sort_by_key(|r| r.metric_index);
+        assert_eq!(
+            ret.iter()
+                .map(|r| (r.metric_index, r.value))
+                .collect::<Vec<_>>(),
+            vec![(0, 5.0), (1, 2.5)]
+        );
+    }
+    #[test]
+    fn transformed_metrics_use_the_lookback_days() {
+        let row = |metric: &str, value: f64, day: u32| InputDataRow {
+            value,
+            date: Some(NaiveDate::from_ymd(2020, 6, day)),
+            marketing_node: Some("node".to_owned()),
+            metric_name: metric.to_owned(),
+            ad_platform: "mock".to_owned(),
+            property_attributes: PropertyAttributes::default(),
+            attribution: None,
+        };
+        let query = QuasrQuery {
+            org_id: "test".to_string(),
+            start_date: NaiveDate::from_ymd(2020, 6, 3),
+            end_date: NaiveDate::from_ymd(2020, 6, 4),
+            metrics: vec![
+                CoreMetric::UpperFunnelMetric("Cost".to_string()),
+                CoreMetric::TransformedMetric {
+                    transform: CoreTransform::RollingAverage { window: 3 },
+                    metric: Box::new(CoreMetric::DivisionMetric {
+                        numerator: set!["Cost"],
+                        denominator: set!["Install"],
+                    }),
+                },
+                CoreMetric::TransformedMetric {
+                    transform: CoreTransform::Cumulative,
+                    metric: Box::new(CoreMetric::UpperFunnelMetric("Cost".to_string())),
+                },
+            ],
+            marketing_node_breakdown: Some(CoreMarketingNodeLevel::Ad),
+            marketing_node_filter: None,
+            ad_platform_breakdown: false,
+            time_breakdown: Some(CoreTimeBreakdown::Day),
+            property_attribute_breakdown: vec![],
+            property_attribute_filter: vec![],
+            include_metadata: false,
+            calendar: CoreCalendar::default(),
+        };
+        assert!(build_sql(&query, &SchemaMapping::default())
+            .as_str()
+            .contains(r#"UpperFunnelMetricValues.date>="2020-06-01""#));
+        let data = vec![
+            row("Cost", 10.0, 1),
+            row("Install", 10.0, 1),
+            row("Cost", 20.0, 3),
+            row("Cost", 30.0, 4),
+            row("Install", 5.0, 4),
+        ];
+        let mut ret = metrics_to_indexed_metrics(query, &data)
+            .into_iter()
+            .map(|r| (r.metric_index, r.start_date.day(), r.value))
+            .collect::<Vec<_>>();
+        ret.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            ret,
+            vec![
+                (0, 3, 20.0),
+                (0, 4, 30.0),
+                // (10 + 20) / 10, then (20 + 30) / 5
+                (1, 3, 3.0),
+                (1, 4, 10.0),
+                (2, 3, 20.0),
+                (2, 4, 50.0),
+            ]
+        );
+    }
+    #[test]
+    fn weekly_breakdown_follows_the_calendar() {
+        let row = |value: f64, day: u32| InputDataRow {
+            value,
+            date: Some(NaiveDate::from_ymd(2020, 6, day)),
+            marketing_node: None,
+            metric_name: "Cost".to_owned(),
+            ad_platform: "mock".to_owned(),
+            property_attributes: PropertyAttributes::default(),
+            attribution: None,
+        };
+        let query = QuasrQuery {
+            org_id: "test".to_string(),
+            start_date: NaiveDate::from_ymd(2020, 6, 2),
+            end_date: NaiveDate::from_ymd(2020, 6, 10),
+            metrics: vec![CoreMetric::UpperFunnelMetric("Cost".to_string())],
+            marketing_node_breakdown: None,
+            marketing_node_filter: None,
+            ad_platform_breakdown: false,
+            time_breakdown: Some(CoreTimeBreakdown::Week),
+            property_attribute_breakdown: vec![],
+            property_attribute_filter: vec![],
+            include_metadata: false,
+            calendar: CoreCalendar {
+                week_start: chrono::Weekday::Sun,
+                fiscal: None,
+            },
+        };
+        // 2020-06-06 is a Saturday
+        let data = vec![row(1.0, 2), row(2.0, 6), row(4.0, 7), row(8.0, 10)];
+        let mut ret = metrics_to_indexed_metrics(query, &data)
+            .into_iter()
+            .map(|r| (r.start_date, r.end_date, r.value))
+            .collect::<Vec<_>>();
+        ret.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            ret,
+            vec![
+                (
+                    NaiveDate::from_ymd(2020, 6, 2),
+                    NaiveDate::from_ymd(2020, 6, 6),
+                    3.0
+                ),
+                (
+                    NaiveDate::from_ymd(2020, 6, 7),
+                    NaiveDate::from_ymd(2020, 6, 10),
+                    12.0
+                ),
+            ]
+        );
+    }
+    #[test]
     fn test_complex_metrics() {
         // Adsflow query fixture
         let core_query: QuasrQuery = QuasrQuery {
@@ -259,6 +833,10 @@ mod tests {
             marketing_node_filter: None,
             ad_platform_breakdown: false,
             time_breakdown: Option::from(CoreTimeBreakdown::Day),
+            property_attribute_breakdown: vec![],
+            property_attribute_filter: vec![],
+            include_metadata: false,
+            calendar: CoreCalendar::default(),
         };
         let db_mock = vec![
             InputDataRow {
@@ -267,6 +845,8 @@ mod tests {
                 marketing_node: Option::from("test_node".to_owned()),
                 metric_name: "Cost".to_owned(),
                 ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution: None,
             },
             InputDataRow {
                 value: 2.0,
@@ -274,6 +854,8 @@ mod tests {
                 marketing_node: Option::from("test_node".to_owned()),
                 metric_name: "Cost".to_owned(),
                 ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution: None,
             },
             InputDataRow {
                 value: 4.0,
@@ -281,9 +863,11 @@ mod tests {
                 marketing_node: Option::from("test_node".to_owned()),
                 metric_name: "Install".to_owned(),
                 ad_platform: "mock".to_owned(),
+                property_attributes: PropertyAttributes::default(),
+                attribution: None,
             },
         ];
-        let mut ret = metrics_to_indexed_metrics(core_query, db_mock);
+        let mut ret = metrics_to_indexed_metrics(core_query, &db_mock);
         let mut expected = vec![
             OutputDataRow {
                 value: 1.0,
@@ -292,6 +876,8 @@ mod tests {
                 metric_index: 0,
                 marketing_node: Option::from("test_node".to_owned()),
                 ad_platform: Option::from("mock".to_owned()),
+                property_attributes: PropertyAttributes::default(),
+                node_details: None,
             },
             OutputDataRow {
                 value: 2.0,
@@ -300,6 +886,8 @@ mod tests {
                 metric_index: 0,
                 marketing_node: Option::from("test_node".to_owned()),
                 ad_platform: Option::from("mock".to_owned()),
+                property_attributes: PropertyAttributes::default(),
+                node_details: None,
             },
             // Zero because that day there are no Installs
             OutputDataRow {
@@ -309,6 +897,8 @@ mod tests {
                 metric_index: 1,
                 marketing_node: Option::from("test_node".to_owned()),
                 ad_platform: Option::from("mock".to_owned()),
+                property_attributes: PropertyAttributes::default(),
+                node_details: None,
             },
             //4/(4+1)=0.8
             OutputDataRow {
@@ -318,6 +908,8 @@ mod tests {
                 metric_index: 1,
                 marketing_node: Option::from("test_node".to_owned()),
                 ad_platform: Option::from("mock".to_owned()),
+                property_attributes: PropertyAttributes::default(),
+                node_details: None,
             },
         ];
         assert_eq!(ret.len(), expected.len());
@@ -335,4 +927,28 @@ mod tests {
             );
         }
     }
+    #[test]
+    fn metric_labels() {
+        let cpa = CoreMetric::DivisionMetric {
+            numerator: set!["Cost"],
+            denominator: set!["Purchases", "Leads"],
+        };
+        assert_eq!(cpa.label(), "Cost / (Leads + Purchases)");
+        assert!(cpa.is_division());
+        let metric = CoreMetric::TransformedMetric {
+            transform: CoreTransform::RollingSum { window: 7 },
+            metric: Box::new(CoreMetric::AttributedMetric {
+                attribution: CoreAttribution {
+                    mode: "click".to_owned(),
+                    window: 28,
+                },
+                metric: Box::new(CoreMetric::UpperFunnelMetric("Purchases".to_owned())),
+            }),
+        };
+        assert_eq!(
+            metric.label(),
+            "Purchases (28 day click) (7 day rolling sum)"
+        );
+        assert!(!metric.is_division());
+    }
 }
@@ -0,0 +1,88 @@
+use crate::input::{CoreMarketingNodeLevel, QuasrQuery};
+
+/// Guardrails for the queries an organisation can run. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoreQueryLimits {
+    /// Most days a query without a marketing node breakdown can span
+    pub max_days: Option<i64>,
+    pub max_days_campaign: Option<i64>,
+    pub max_days_ad_set: Option<i64>,
+    pub max_days_ad: Option<i64>,
+    /// Most rows the database may return for a query
+    pub max_rows: Option<usize>,
+    /// Passed to the database, which stops the query after that long
+    pub max_execution_time_ms: Option<u64>,
+}
+impl CoreQueryLimits {
+    fn max_days_for(&self, level: Option<CoreMarketingNodeLevel>) -> Option<i64> {
+        match level {
+            None => self.max_days,
+            Some(CoreMarketingNodeLevel::Campaign) => self.max_days_campaign,
+            Some(CoreMarketingNodeLevel::AdSet) => self.max_days_ad_set,
+            Some(CoreMarketingNodeLevel::Ad) => self.max_days_ad,
+        }
+    }
+    /// Checks the days the query fetches, including the ones its transforms look back on,
+    /// against the limit for its breakdown level
+    pub fn check_span(&self, q: &QuasrQuery) -> Result<(), String> {
+        let days = (q.end_date - q.fetch_start_date()).num_days() + 1;
+        match self.max_days_for(q.marketing_node_breakdown) {
+            Some(max_days) if days > max_days => Err(format!(
+                "The query spans {} days, but {} can span at most {}",
+                days,
+                match q.marketing_node_breakdown {
+                    None => "queries without a marketing node breakdown",
+                    Some(CoreMarketingNodeLevel::Campaign) => "queries broken down by campaign",
+                    Some(CoreMarketingNodeLevel::AdSet) => "queries broken down by ad set",
+                    Some(CoreMarketingNodeLevel::Ad) => "queries broken down by ad",
+                },
+                max_days
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CoreQueryLimits;
+    use crate::{
+        calendar::CoreCalendar,
+        input::{CoreMarketingNodeLevel, QuasrQuery},
+    };
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_check_span() {
+        let mut query = QuasrQuery {
+            metrics: vec![],
+            org_id: "org".to_owned(),
+            start_date: NaiveDate::from_ymd(2020, 1, 1),
+            end_date: NaiveDate::from_ymd(2020, 3, 31),
+            marketing_node_breakdown: Some(CoreMarketingNodeLevel::Ad),
+            marketing_node_filter: None,
+            ad_platform_breakdown: false,
+            time_breakdown: None,
+            property_attribute_breakdown: vec![],
+            property_attribute_filter: vec![],
+            include_metadata: false,
+            calendar: CoreCalendar::default(),
+        };
+        let limits = CoreQueryLimits {
+            max_days_ad: Some(31),
+            ..CoreQueryLimits::default()
+        };
+        assert_eq!(
+            limits.check_span(&query),
+            Err(
+                "The query spans 91 days, but queries broken down by ad can span at most 31"
+                    .to_owned()
+            )
+        );
+        query.marketing_node_breakdown = Some(CoreMarketingNodeLevel::Campaign);
+        assert_eq!(limits.check_span(&query), Ok(()));
+        query.marketing_node_breakdown = Some(CoreMarketingNodeLevel::Ad);
+        query.start_date = NaiveDate::from_ymd(2020, 3, 1);
+        assert_eq!(limits.check_span(&query), Ok(()));
+    }
+}
@@ -1,11 +1,19 @@
 use crate::{
-    input::{CoreTimeBreakdown, InputDataVec, QuasrQuery},
+    input::{
+        CoreAttribution, CoreTimeBreakdown, InputDataRow, InputDataVec, PropertyAttributes,
+        QuasrQuery,
+    },
     MarketingNode, MetricName, OutputDataRow, OutputDataVec,
 };
 use chrono::NaiveDate;
 use std::collections::{HashMap, HashSet};
-type DateNodeTuple = (Option<NaiveDate>, Option<MarketingNode>, String);
-fn do_qs_divide(numerator: f64, denominator: f64) -> f64 {
+type DateNodeTuple = (
+    Option<NaiveDate>,
+    Option<MarketingNode>,
+    String,
+    PropertyAttributes,
+);
+pub fn do_qs_divide(numerator: f64, denominator: f64) -> f64 {
     if denominator == 0.0 {
         0.0
     } else {
@@ -24,12 +32,22 @@ pub fn get_division_metric_from_metrics(
     data.iter().for_each(|d| {
         if numerator.contains(&d.metric_name) {
             *numerators
-                .entry((d.date, d.marketing_node.clone(), d.ad_platform.clone()))
+                .entry((
+                    d.date,
+                    d.marketing_node.clone(),
+                    d.ad_platform.clone(),
+                    d.property_attributes.clone(),
+                ))
                 .or_insert(0.0) += d.value;
         }
         if denominator.contains(&d.metric_name) {
             *denominators
-                .entry((d.date, d.marketing_node.clone(), d.ad_platform.clone()))
+                .entry((
+                    d.date,
+                    d.marketing_node.clone(),
+                    d.ad_platform.clone(),
+                    d.property_attributes.clone(),
+                ))
                 .or_insert(0.0) += d.value;
         }
     });
@@ -37,33 +55,39 @@ pub fn get_division_metric_from_metrics(
     let all_keys: HashSet<&DateNodeTuple> = numerators.keys().chain(denominators.keys()).collect();
     all_keys
         .into_iter()
-        .map(|(date, marketing_node, ad_platform)| OutputDataRow {
-            metric_index: idx,
-            marketing_node: marketing_node.clone(),
-            ad_platform: Some(ad_platform.clone()),
-            value: do_qs_divide(
-                *numerators
-                    .get(&(*date, marketing_node.clone(), ad_platform.clone()))
-                    .unwrap_or(&0.0),
-                *denominators
-                    .get(&(*date, marketing_node.clone(), ad_platform.clone()))
-                    .unwrap_or(&0.0),
-            ),
-            start_date: get_filter_date_or_self_date(*date, query.start_date, query.time_breakdown),
-            end_date: get_filter_date_or_self_date(*date, query.end_date, query.time_breakdown),
+        .map(|key| {
+            let (start_date, end_date) = get_filter_date_or_self_date(key.0, query);
+            OutputDataRow {
+                metric_index: idx,
+                marketing_node: key.1.clone(),
+                ad_platform: Some(key.2.clone()),
+                value: do_qs_divide(
+                    *numerators.get(key).unwrap_or(&0.0),
+                    *denominators.get(key).unwrap_or(&0.0),
+                ),
+                start_date,
+                end_date,
+                property_attributes: key.3.clone(),
+                node_details: None,
+            }
         })
         .collect()
 }
+/// Start and end date of an output row
 pub fn get_filter_date_or_self_date(
     self_date: Option<NaiveDate>,
-    filter_date: NaiveDate,
-    breakdown: Option<CoreTimeBreakdown>,
-) -> NaiveDate {
-    match (self_date, breakdown) {
-        // If there is no breakdown, the date is the filter date
-        (_, None) => filter_date,
-        // If there is a daily breakdown, the date is the self date if available
-        (Some(d), Some(CoreTimeBreakdown::Day)) => d,
+    query: &QuasrQuery,
+) -> (NaiveDate, NaiveDate) {
+    match (self_date, query.time_breakdown) {
+        // If there is no breakdown, the dates are the filter dates
+        (_, None) => (query.start_date, query.end_date),
+        // If there is a daily breakdown, they are the self date
+        (Some(d), Some(CoreTimeBreakdown::Day)) => (d, d),
+        // Otherwise they are the week or month of the self date, within the filter dates
+        (Some(d), Some(breakdown)) => {
+            let (start, end) = query.calendar.bucket(d, breakdown);
+            (start.max(query.start_date), end.min(query.end_date))
+        }
         _ => panic!("Unexpected!"),
     }
 }
@@ -76,18 +100,88 @@ pub fn get_summation_metric_from_metrics(
     let mut ret: HashMap<DateNodeTuple, f64> = HashMap::new();
     data.iter().for_each(|d| {
         if metrics.contains(&d.metric_name) {
-            *ret.entry((d.date, d.marketing_node.clone(), d.ad_platform.clone()))
-                .or_insert(0.0) += d.value;
+            *ret.entry((
+                d.date,
+                d.marketing_node.clone(),
+                d.ad_platform.clone(),
+                d.property_attributes.clone(),
+            ))
+            .or_insert(0.0) += d.value;
         }
     });
     ret.into_iter()
-        .map(|(k, v)| OutputDataRow {
-            start_date: get_filter_date_or_self_date(k.0, query.start_date, query.time_breakdown),
-            end_date: get_filter_date_or_self_date(k.0, query.end_date, query.time_breakdown),
-            metric_index: idx,
+        .map(|(k, v)| {
+            let (start_date, end_date) = get_filter_date_or_self_date(k.0, query);
+            OutputDataRow {
+                start_date,
+                end_date,
+                metric_index: idx,
+                marketing_node: k.1,
+                value: v,
+                ad_platform: Some(k.2),
+                property_attributes: k.3,
+                node_details: None,
+            }
+        })
+        .collect()
+}
+/// Sums the values of rows that only differ in their attribution
+pub fn merge_attributions<'a>(data: impl Iterator<Item = &'a InputDataRow>) -> InputDataVec {
+    let mut ret: HashMap<(DateNodeTuple, MetricName), f64> = HashMap::new();
+    data.for_each(|d| {
+        *ret.entry((
+            (
+                d.date,
+                d.marketing_node.clone(),
+                d.ad_platform.clone(),
+                d.property_attributes.clone(),
+            ),
+            d.metric_name.clone(),
+        ))
+        .or_insert(0.0) += d.value;
+    });
+    ret.into_iter()
+        .map(|((k, metric_name), value)| InputDataRow {
+            value,
+            date: k.0,
+            metric_name,
+            marketing_node: k.1,
+            ad_platform: k.2,
+            property_attributes: k.3,
+            attribution: None,
+        })
+        .collect()
+}
+/// Moves the rows to the first day of their week or month, summing the ones that end up the same
+pub fn bucket_dates(data: &InputDataVec, query: &QuasrQuery) -> InputDataVec {
+    let mut ret: HashMap<(DateNodeTuple, MetricName, Option<CoreAttribution>), f64> =
+        HashMap::new();
+    data.iter().for_each(|d| {
+        let date = match (d.date, query.time_breakdown) {
+            (Some(date), Some(breakdown)) => Some(query.calendar.bucket(date, breakdown).0),
+            _ => d.date,
+        };
+        *ret.entry((
+            (
+                date,
+                d.marketing_node.clone(),
+                d.ad_platform.clone(),
+                d.property_attributes.clone(),
+            ),
+            d.metric_name.clone(),
+            d.attribution.clone(),
+        ))
+        .or_insert(0.0) += d.value;
+    });
+    ret.into_iter()
+        .map(|((k, metric_name, attribution), value)| InputDataRow {
+            value,
+            date: k.0,
+            metric_name,
             marketing_node: k.1,
-            value: v,
-            ad_platform: Some(k.2),
+            ad_platform: k.2,
+            property_attributes: k.3,
+            attribution,
         })
         .collect()
 }
@@ -0,0 +1,192 @@
+use crate::{input::MarketingNodeDetails, MarketingNode, OutputDataRow};
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PacingStatus {
+    OnTrack,
+    OverDelivering,
+    UnderDelivering,
+    /// The node has no daily budget of its own
+    NoBudget,
+}
+/// Period a pacing report covers, spend is counted up to and including `as_of`
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PacingPeriod {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub as_of: NaiveDate,
+}
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PacingRow {
+    pub marketing_node: MarketingNode,
+    pub name: Option<String>,
+    pub daily_budget: Option<f64>,
+    pub budget_to_date: Option<f64>,
+    pub spend_to_date: f64,
+    /// Spend to date over budget to date
+    pub pacing: Option<f64>,
+    /// End-of-period spend if the node keeps spending at its average daily rate so far
+    pub projected_spend: f64,
+    pub period_budget: Option<f64>,
+    pub status: PacingStatus,
+}
+impl PacingPeriod {
+    /// `None` if the period hasn't started by `as_of`. Days after the end of the period count as
+    /// its last day.
+    pub fn new(start_date: NaiveDate, end_date: NaiveDate, as_of: NaiveDate) -> Option<Self> {
+        if as_of < start_date || end_date < start_date {
+            return None;
+        }
+        Some(PacingPeriod {
+            start_date,
+            end_date,
+            as_of: as_of.min(end_date),
+        })
+    }
+    fn days(&self) -> f64 {
+        ((self.end_date - self.start_date).num_days() + 1) as f64
+    }
+    fn elapsed_days(&self) -> f64 {
+        ((self.as_of - self.start_date).num_days() + 1) as f64
+    }
+}
+/// Paces the spend of every node in `spend`, and of every node in `details` with a daily
+/// budget, against its daily budget. Nodes whose projected spend differs from the period's
+/// budget by more than `tolerance`, a fraction of the budget, are flagged. Budget changes aren't
+/// recorded, so the current daily budget is used for the whole period.
+pub fn pace_nodes(
+    spend: &[OutputDataRow],
+    details: &HashMap<MarketingNode, MarketingNodeDetails>,
+    period: &PacingPeriod,
+    tolerance: f64,
+) -> Vec<PacingRow> {
+    let mut spend_by_node: BTreeMap<&MarketingNode, f64> = BTreeMap::new();
+    for row in spend {
+        if let Some(node) = &row.marketing_node {
+            *spend_by_node.entry(node).or_insert(0.0) += row.value;
+        }
+    }
+    // Budgeted nodes that haven't spent anything are the furthest behind
+    for (node, node_details) in details {
+        if matches!(node_details.daily_budget, Some(b) if b > 0.0) {
+            spend_by_node.entry(node).or_insert(0.0);
+        }
+    }
+    spend_by_node
+        .into_iter()
+        .map(|(node, spend_to_date)| {
+            let node_details = details.get(node);
+            let daily_budget = node_details
+                .and_then(|d| d.daily_budget)
+                .filter(|b| *b > 0.0);
+            let projected_spend = spend_to_date / period.elapsed_days() * period.days();
+            let budget_to_date = daily_budget.map(|b| b * period.elapsed_days());
+            let period_budget = daily_budget.map(|b| b * period.days());
+            let status = match period_budget {
+                None => PacingStatus::NoBudget,
+                Some(budget) if projected_spend > budget * (1.0 + tolerance) => {
+                    PacingStatus::OverDelivering
+                }
+                Some(budget) if projected_spend < budget * (1.0 - tolerance) => {
+                    PacingStatus::UnderDelivering
+                }
+                Some(_) => PacingStatus::OnTrack,
+            };
+            PacingRow {
+                marketing_node: node.clone(),
+                name: node_details.map(|d| d.name.clone()),
+                daily_budget,
+                budget_to_date,
+                spend_to_date,
+                pacing: budget_to_date.map(|b| spend_to_date / b),
+                projected_spend,
+                period_budget,
+                status,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pace_nodes, PacingPeriod, PacingStatus};
+    use crate::{
+        input::{MarketingNodeDetails, PropertyAttributes},
+        OutputDataRow,
+    };
+    use chrono::NaiveDate;
+    use std::collections::HashMap;
+
+    fn spend(node: &str, value: f64) -> OutputDataRow {
+        OutputDataRow {
+            value,
+            start_date: NaiveDate::from_ymd(2020, 6, 1),
+            end_date: NaiveDate::from_ymd(2020, 6, 10),
+            metric_index: 0,
+            marketing_node: Some(node.to_owned()),
+            ad_platform: None,
+            property_attributes: PropertyAttributes::default(),
+            node_details: None,
+        }
+    }
+    fn details(daily_budget: Option<f64>) -> MarketingNodeDetails {
+        MarketingNodeDetails {
+            name: "Campaign".to_owned(),
+            handle: None,
+            property_status: None,
+            external_created_at: None,
+            campaign_id: None,
+            ad_set_id: None,
+            daily_budget,
+        }
+    }
+
+    #[test]
+    fn test_pace_nodes() {
+        let d = NaiveDate::from_ymd;
+        assert!(PacingPeriod::new(d(2020, 6, 1), d(2020, 6, 30), d(2020, 5, 31)).is_none());
+        let period = PacingPeriod::new(d(2020, 6, 1), d(2020, 6, 30), d(2020, 6, 10)).unwrap();
+        let mut nodes = HashMap::new();
+        nodes.insert("on_track".to_owned(), details(Some(10.0)));
+        nodes.insert("over".to_owned(), details(Some(10.0)));
+        nodes.insert("under".to_owned(), details(Some(10.0)));
+        nodes.insert("no_budget".to_owned(), details(None));
+        nodes.insert("idle".to_owned(), details(Some(10.0)));
+        let rows = vec![
+            spend("on_track", 60.0),
+            spend("on_track", 45.0),
+            spend("over", 150.0),
+            spend("under", 50.0),
+            spend("no_budget", 50.0),
+        ];
+        let paced = pace_nodes(&rows, &nodes, &period, 0.1);
+        let summary: Vec<(&str, f64, Option<f64>, PacingStatus)> = paced
+            .iter()
+            .map(|r| {
+                (
+                    r.marketing_node.as_str(),
+                    r.projected_spend,
+                    r.pacing,
+                    r.status,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("idle", 0.0, Some(0.0), PacingStatus::UnderDelivering),
+                ("no_budget", 150.0, None, PacingStatus::NoBudget),
+                ("on_track", 315.0, Some(1.05), PacingStatus::OnTrack),
+                ("over", 450.0, Some(1.5), PacingStatus::OverDelivering),
+                ("under", 150.0, Some(0.5), PacingStatus::UnderDelivering),
+            ]
+        );
+        assert_eq!(paced[2].budget_to_date, Some(100.0));
+        assert_eq!(paced[2].period_budget, Some(300.0));
+    }
+}
@@ -1,14 +1,40 @@
-use crate::{input::QuasrQuery, CoreMetric};
+use crate::{
+    input::{CorePropertyAttribute, QuasrQuery},
+    schema::SchemaMapping,
+    MetricName,
+};
 use std::collections::HashSet;
 
+/// A `WHERE` condition, with a `?` placeholder for each of its `params`
+pub struct Condition {
+    pub sql: String,
+    pub params: Vec<String>,
+}
+impl From<String> for Condition {
+    fn from(sql: String) -> Self {
+        Condition {
+            sql,
+            params: vec![],
+        }
+    }
+}
+/// `column IN (?,?,...)`, with `values` bound to the placeholders
+fn in_condition(column: String, values: Vec<String>) -> Condition {
+    Condition {
+        sql: format!("{} IN ({})", column, vec!["?"; values.len()].join(",")),
+        params: values,
+    }
+}
+/// Renders its part of the query, with the table and column names of the `SchemaMapping`.
+/// Values from the query are never pasted into the SQL, filters bind them as parameters.
 pub trait Processor {
-    fn select(&self, _: &QuasrQuery) -> Vec<String> {
+    fn select(&self, _: &QuasrQuery, _: &SchemaMapping) -> Vec<String> {
         vec![]
     }
-    fn filter(&self, _: &QuasrQuery) -> Vec<String> {
+    fn filter(&self, _: &QuasrQuery, _: &SchemaMapping) -> Vec<Condition> {
         vec![]
     }
-    fn groupby(&self, _: &QuasrQuery) -> Vec<String> {
+    fn groupby(&self, _: &QuasrQuery, _: &SchemaMapping) -> Vec<String> {
         vec![]
     }
 }
@@ -18,27 +44,42 @@ pub struct TimeFilter;
 pub struct TimeBreakdown;
 pub struct MarketingNodeFilter;
 pub struct MetricSelector;
+pub struct AttributionBreakdown;
+pub struct PropertyAttributeBreakdown;
+pub struct PropertyAttributeFilter;
 impl Processor for BaseFilter {
-    fn select(&self, _: &QuasrQuery) -> Vec<String> {
+    fn select(&self, _: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
         vec![
-            "SUM(sourceValue) AS sourceValue".to_owned(),
-            "UpperFunnelMetricFields.name as name".to_owned(),
+            format!("SUM({}) AS sourceValue", s.values.col(&s.values.value)),
+            format!("{} as name", s.fields.col(&s.fields.name)),
             "\"Twitter\" as ad_platform".to_owned(),
         ]
     }
 
-    fn filter(&self, q: &QuasrQuery) -> Vec<String> {
+    fn filter(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<Condition> {
         vec![
-            format!("UpperFunnelMetricFields.organizationId=\"{}\"", q.org_id),
-            "UpperFunnelMetricFields.id=UpperFunnelMetricValues.upperFunnelMetricFieldId"
-                .to_owned(),
-            "Properties.id=UpperFunnelMetricValues.propertyId".to_owned(),
+            Condition {
+                sql: format!("{}=?", s.fields.col(&s.fields.organization_id)),
+                params: vec![q.org_id.clone()],
+            },
+            format!(
+                "{}={}",
+                s.fields.col(&s.fields.id),
+                s.values.col(&s.values.field_id)
+            )
+            .into(),
+            format!(
+                "{}={}",
+                s.properties.col(&s.properties.id),
+                s.values.col(&s.values.property_id)
+            )
+            .into(),
         ]
     }
 
-    fn groupby(&self, _: &QuasrQuery) -> Vec<String> {
+    fn groupby(&self, _: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
         vec![
-            "UpperFunnelMetricFields.name".to_owned(),
+            s.fields.col(&s.fields.name),
             "ad_platform".to_owned(),
             "qdate".to_owned(),
         ]
@@ -46,88 +87,181 @@ impl Processor for BaseFilter {
 }
 
 impl Processor for MarketingNodeBreakdown {
-    fn select(&self, q: &QuasrQuery) -> Vec<String> {
+    fn select(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
         match q.marketing_node_breakdown {
             Some(q) => vec![format!(
-                "Properties.{} AS marketing_node",
-                q.to_database_column_id_string()
+                "{} AS marketing_node",
+                s.properties.col(s.properties.node_id(q))
             )],
             None => vec!["NULL as marketing_node".to_owned()],
         }
     }
 
-    fn groupby(&self, q: &QuasrQuery) -> Vec<String> {
+    fn groupby(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
         match q.marketing_node_breakdown {
-            Some(q) => vec![format!("Properties.{}", q.to_database_column_id_string())],
+            Some(q) => vec![s.properties.col(s.properties.node_id(q))],
             None => vec![],
         }
     }
 }
 impl Processor for TimeFilter {
-    fn filter(&self, q: &QuasrQuery) -> Vec<String> {
+    fn filter(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<Condition> {
         vec![
-            format!("date>=\"{}\" ", q.start_date),
-            format!("date<=\"{}\" ", q.end_date),
+            format!(
+                "{}>=\"{}\" ",
+                s.values.col(&s.values.date),
+                q.fetch_start_date()
+            )
+            .into(),
+            format!("{}<=\"{}\" ", s.values.col(&s.values.date), q.end_date).into(),
         ]
     }
 }
 impl Processor for TimeBreakdown {
-    fn select(&self, q: &QuasrQuery) -> Vec<String> {
+    fn select(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
         vec![{
             if let Some(_) = q.time_breakdown {
-                "date AS qdate"
+                format!("{} AS qdate", s.values.col(&s.values.date))
             } else {
-                "NULL as qdate"
+                "NULL as qdate".to_owned()
             }
-        }
-        .to_string()]
+        }]
     }
 }
 impl Processor for MarketingNodeFilter {
-    fn select(&self, q: &QuasrQuery) -> Vec<String> {
+    fn filter(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<Condition> {
         if let Some(mnode_filter) = &q.marketing_node_filter {
-            let mnode_string = mnode_filter
-                .value
-                .iter()
-                .map(|i| format!("\"{}\"", i))
-                .collect::<Vec<String>>()
-                .join(",");
-            vec![format!(
-                "Properties.{} IN ({})",
-                mnode_filter.level.to_database_column_id_string(),
-                mnode_string
+            vec![in_condition(
+                s.properties.col(s.properties.node_id(mnode_filter.level)),
+                mnode_filter.value.clone(),
             )]
         } else {
             vec![]
         }
     }
 }
+fn sorted_metric_names(names: &HashSet<MetricName>) -> Vec<String> {
+    let mut names: Vec<String> = names.iter().cloned().collect();
+    names.sort();
+    names
+}
 impl Processor for MetricSelector {
-    fn filter(&self, q: &QuasrQuery) -> Vec<String> {
-        let unique_base_metric_names = q
-            .metrics
+    fn filter(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<Condition> {
+        let name = s.fields.col(&s.fields.name);
+        if !q.has_attributed_metrics() {
+            return vec![in_condition(
+                name,
+                sorted_metric_names(&q.base_metric_names()),
+            )];
+        }
+        let mode = s.fields.col(&s.fields.attribution_mode);
+        let window = s.fields.col(&s.fields.attribution_window);
+        // Attributed metrics only match fields with their attribution, or without any
+        let mut conditions = q
+            .base_metric_names_by_attribution()
+            .into_iter()
+            .map(|(attribution, names)| {
+                let names = in_condition(name.clone(), sorted_metric_names(&names));
+                match attribution {
+                    None => names,
+                    Some(attribution) => Condition {
+                        sql: format!(
+                            "({} AND ({mode} IS NULL OR ({mode}=? AND {window}=?)))",
+                            names.sql,
+                            mode = mode,
+                            window = window
+                        ),
+                        params: names
+                            .params
+                            .into_iter()
+                            .chain(vec![attribution.mode, attribution.window.to_string()])
+                            .collect(),
+                    },
+                }
+            })
+            .collect::<Vec<Condition>>();
+        conditions.sort_by(|a, b| (&a.sql, &a.params).cmp(&(&b.sql, &b.params)));
+        vec![Condition {
+            sql: format!(
+                "({})",
+                conditions
+                    .iter()
+                    .map(|c| c.sql.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" OR ")
+            ),
+            params: conditions.into_iter().flat_map(|c| c.params).collect(),
+        }]
+    }
+}
+impl Processor for AttributionBreakdown {
+    fn select(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
+        if q.has_attributed_metrics() {
+            vec![
+                format!(
+                    "{} AS attribution_mode",
+                    s.fields.col(&s.fields.attribution_mode)
+                ),
+                format!(
+                    "{} AS attribution_window",
+                    s.fields.col(&s.fields.attribution_window)
+                ),
+            ]
+        } else {
+            vec![
+                "NULL as attribution_mode".to_owned(),
+                "NULL as attribution_window".to_owned(),
+            ]
+        }
+    }
+
+    fn groupby(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
+        if q.has_attributed_metrics() {
+            vec![
+                s.fields.col(&s.fields.attribution_mode),
+                s.fields.col(&s.fields.attribution_window),
+            ]
+        } else {
+            vec![]
+        }
+    }
+}
+impl Processor for PropertyAttributeBreakdown {
+    fn select(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
+        CorePropertyAttribute::all()
             .iter()
-            .map(|m| match m {
-                CoreMetric::UpperFunnelMetric(metric_name) => vec![format!("\"{}\"", metric_name)],
-                CoreMetric::SummationMetric(metrics) => {
-                    metrics.iter().map(|m| format!("\"{}\"", m)).collect()
+            .map(|attribute| {
+                if q.property_attribute_breakdown.contains(attribute) {
+                    format!(
+                        "{} AS {}",
+                        s.properties.col(s.properties.attribute(*attribute)),
+                        attribute.to_alias_string()
+                    )
+                } else {
+                    format!("NULL as {}", attribute.to_alias_string())
                 }
-                CoreMetric::DivisionMetric {
-                    denominator,
-                    numerator,
-                } => denominator
-                    .union(numerator)
-                    .map(|i| format!("\"{}\"", i))
-                    .collect(),
             })
-            .flatten()
-            .collect::<HashSet<String>>()
-            .into_iter()
-            .collect::<Vec<String>>()
-            .join(",");
-        vec![format!(
-            "UpperFunnelMetricFields.name IN ({})",
-            unique_base_metric_names
-        )]
+            .collect()
+    }
+
+    fn groupby(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<String> {
+        q.property_attribute_breakdown
+            .iter()
+            .map(|attribute| s.properties.col(s.properties.attribute(*attribute)))
+            .collect()
+    }
+}
+impl Processor for PropertyAttributeFilter {
+    fn filter(&self, q: &QuasrQuery, s: &SchemaMapping) -> Vec<Condition> {
+        q.property_attribute_filter
+            .iter()
+            .map(|attribute_filter| {
+                in_condition(
+                    s.properties
+                        .col(s.properties.attribute(attribute_filter.attribute)),
+                    attribute_filter.value.clone(),
+                )
+            })
+            .collect()
     }
 }
@@ -0,0 +1,242 @@
+use crate::input::{CoreMarketingNodeLevel, CorePropertyAttribute};
+use serde::{Deserialize, Serialize};
+
+/// Table with one row per marketing node, day and metric
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValuesTable {
+    pub table: String,
+    pub field_id: String,
+    pub property_id: String,
+    pub date: String,
+    pub value: String,
+    pub ad_platform: String,
+}
+/// Table with the metrics an organisation has
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldsTable {
+    pub table: String,
+    pub id: String,
+    pub organization_id: String,
+    pub name: String,
+    pub attribution_mode: String,
+    pub attribution_window: String,
+    pub has_currency: String,
+    pub is_lower_funnel: String,
+    pub calculation_mode: String,
+    pub lower_funnel_metric_name: String,
+}
+/// Table with one row per campaign, ad set and ad
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PropertiesTable {
+    pub table: String,
+    pub id: String,
+    pub name: String,
+    pub handle: String,
+    pub account_id: String,
+    pub campaign_id: String,
+    pub ad_set_id: String,
+    pub ad_id: String,
+    pub objective: String,
+    pub property_status: String,
+    pub display_status: String,
+    pub bid_strategy: String,
+    pub property_type: String,
+    pub external_created_at: String,
+    pub daily_budget: String,
+}
+/// Names of the tables and columns the SQL is generated for. The default is the AdsFlow
+/// schema, other data products with the same shape can rename any of them.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaMapping {
+    /// Database the tables are in, the connection's one if unset
+    pub database: Option<String>,
+    pub values: ValuesTable,
+    pub fields: FieldsTable,
+    pub properties: PropertiesTable,
+}
+
+fn s(name: &str) -> String {
+    name.to_owned()
+}
+impl Default for ValuesTable {
+    fn default() -> Self {
+        ValuesTable {
+            table: s("UpperFunnelMetricValues"),
+            field_id: s("upperFunnelMetricFieldId"),
+            property_id: s("propertyId"),
+            date: s("date"),
+            value: s("sourceValue"),
+            ad_platform: s("adPlatform"),
+        }
+    }
+}
+impl Default for FieldsTable {
+    fn default() -> Self {
+        FieldsTable {
+            table: s("UpperFunnelMetricFields"),
+            id: s("id"),
+            organization_id: s("organizationId"),
+            name: s("name"),
+            attribution_mode: s("attributionMode"),
+            attribution_window: s("attributionWindow"),
+            has_currency: s("hasCurrency"),
+            is_lower_funnel: s("isLowerFunnel"),
+            calculation_mode: s("calculationMode"),
+            lower_funnel_metric_name: s("lowerFunnelMetricName"),
+        }
+    }
+}
+impl Default for PropertiesTable {
+    fn default() -> Self {
+        PropertiesTable {
+            table: s("Properties"),
+            id: s("id"),
+            name: s("name"),
+            handle: s("handle"),
+            account_id: s("accountId"),
+            campaign_id: s("campaignId"),
+            ad_set_id: s("adSetId"),
+            ad_id: s("adId"),
+            objective: s("objective"),
+            property_status: s("propertyStatus"),
+            display_status: s("displayStatus"),
+            bid_strategy: s("bidStrategy"),
+            property_type: s("propertyType"),
+            external_created_at: s("externalCreatedAt"),
+            daily_budget: s("dailyBudget"),
+        }
+    }
+}
+impl ValuesTable {
+    /// `column` qualified with the table name
+    pub fn col(&self, column: &str) -> String {
+        format!("{}.{}", self.table, column)
+    }
+}
+impl FieldsTable {
+    /// `column` qualified with the table name
+    pub fn col(&self, column: &str) -> String {
+        format!("{}.{}", self.table, column)
+    }
+}
+impl PropertiesTable {
+    /// `column` qualified with the table name
+    pub fn col(&self, column: &str) -> String {
+        format!("{}.{}", self.table, column)
+    }
+    /// Column with the id of the node's campaign, ad set or ad
+    pub fn node_id(&self, level: CoreMarketingNodeLevel) -> &str {
+        match level {
+            CoreMarketingNodeLevel::Campaign => &self.campaign_id,
+            CoreMarketingNodeLevel::AdSet => &self.ad_set_id,
+            CoreMarketingNodeLevel::Ad => &self.ad_id,
+        }
+    }
+    pub fn attribute(&self, attribute: CorePropertyAttribute) -> &str {
+        match attribute {
+            CorePropertyAttribute::Objective => &self.objective,
+            CorePropertyAttribute::PropertyStatus => &self.property_status,
+            CorePropertyAttribute::DisplayStatus => &self.display_status,
+            CorePropertyAttribute::BidStrategy => &self.bid_strategy,
+            CorePropertyAttribute::PropertyType => &self.property_type,
+        }
+    }
+}
+/// Names are pasted into the SQL as they are, so only plain identifiers are allowed
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        }
+        _ => false,
+    }
+}
+impl SchemaMapping {
+    /// `table`, in the mapping's database
+    pub fn qualify(&self, table: &str) -> String {
+        match &self.database {
+            Some(database) => format!("{}.{}", database, table),
+            None => table.to_owned(),
+        }
+    }
+    /// Checks that every name is a valid identifier and that the tables are different
+    pub fn validate(&self) -> Result<(), String> {
+        let v = &self.values;
+        let f = &self.fields;
+        let p = &self.properties;
+        let names = [
+            ("values.table", &v.table),
+            ("values.field_id", &v.field_id),
+            ("values.property_id", &v.property_id),
+            ("values.date", &v.date),
+            ("values.value", &v.value),
+            ("values.ad_platform", &v.ad_platform),
+            ("fields.table", &f.table),
+            ("fields.id", &f.id),
+            ("fields.organization_id", &f.organization_id),
+            ("fields.name", &f.name),
+            ("fields.attribution_mode", &f.attribution_mode),
+            ("fields.attribution_window", &f.attribution_window),
+            ("fields.has_currency", &f.has_currency),
+            ("fields.is_lower_funnel", &f.is_lower_funnel),
+            ("fields.calculation_mode", &f.calculation_mode),
+            (
+                "fields.lower_funnel_metric_name",
+                &f.lower_funnel_metric_name,
+            ),
+            ("properties.table", &p.table),
+            ("properties.id", &p.id),
+            ("properties.name", &p.name),
+            ("properties.handle", &p.handle),
+            ("properties.account_id", &p.account_id),
+            ("properties.campaign_id", &p.campaign_id),
+            ("properties.ad_set_id", &p.ad_set_id),
+            ("properties.ad_id", &p.ad_id),
+            ("properties.objective", &p.objective),
+            ("properties.property_status", &p.property_status),
+            ("properties.display_status", &p.display_status),
+            ("properties.bid_strategy", &p.bid_strategy),
+            ("properties.property_type", &p.property_type),
+            ("properties.external_created_at", &p.external_created_at),
+            ("properties.daily_budget", &p.daily_budget),
+        ];
+        let database = self.database.as_ref().map(|d| ("database", d));
+        for (key, name) in names.iter().cloned().chain(database) {
+            if !is_identifier(name) {
+                return Err(format!(
+                    "{} = \"{}\" is not a valid SQL identifier",
+                    key, name
+                ));
+            }
+        }
+        if v.table == f.table || v.table == p.table || f.table == p.table {
+            return Err("values, fields and properties have to be different tables".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SchemaMapping;
+
+    #[test]
+    fn test_validate() {
+        let mut schema = SchemaMapping::default();
+        assert_eq!(schema.validate(), Ok(()));
+        schema.database = Some("ads_v2".to_owned());
+        schema.properties.table = "Nodes".to_owned();
+        assert_eq!(schema.validate(), Ok(()));
+        assert_eq!(schema.qualify("Nodes"), "ads_v2.Nodes");
+        schema.values.date = "day\"; DROP TABLE Nodes; --".to_owned();
+        assert!(schema.validate().unwrap_err().starts_with("values.date"));
+        schema.values.date = "day".to_owned();
+        schema.fields.table = "Nodes".to_owned();
+        assert!(schema.validate().is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    input::{CoreTransform, PropertyAttributes, QuasrQuery},
+    metric_processing::do_qs_divide,
+    MarketingNode, OutputDataRow, OutputDataVec,
+};
+use chrono::{Duration, NaiveDate};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+type SeriesKey = (Option<MarketingNode>, Option<String>, PropertyAttributes);
+type Series = BTreeMap<NaiveDate, f64>;
+
+/// Splits daily rows into one series per marketing node, ad platform and property attributes
+fn into_series(rows: OutputDataVec) -> HashMap<SeriesKey, Series> {
+    let mut ret: HashMap<SeriesKey, Series> = HashMap::new();
+    rows.into_iter().for_each(|r| {
+        *ret.entry((r.marketing_node, r.ad_platform, r.property_attributes))
+            .or_default()
+            .entry(r.start_date)
+            .or_insert(0.0) += r.value;
+    });
+    ret
+}
+/// Transformed values from `start` to `end`. Days that have no values to transform are skipped.
+fn transform_series(
+    values: &Series,
+    transform: CoreTransform,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Series {
+    let mut ret = Series::new();
+    let mut cumulative: Option<f64> = None;
+    let mut day = start;
+    while day <= end {
+        match transform {
+            CoreTransform::Cumulative => {
+                if let Some(value) = values.get(&day) {
+                    *cumulative.get_or_insert(0.0) += value;
+                }
+                if let Some(value) = cumulative {
+                    ret.insert(day, value);
+                }
+            }
+            CoreTransform::RollingSum { window } | CoreTransform::RollingAverage { window } => {
+                let in_window: Vec<f64> = match day
+                    .checked_sub_signed(Duration::days(i64::from(window) - 1))
+                {
+                    Some(window_start) => values.range(window_start..=day).map(|v| *v.1).collect(),
+                    None => values.range(..=day).map(|v| *v.1).collect(),
+                };
+                if !in_window.is_empty() {
+                    let sum: f64 = in_window.iter().sum();
+                    ret.insert(
+                        day,
+                        match transform {
+                            CoreTransform::RollingAverage { .. } => sum / f64::from(window),
+                            _ => sum,
+                        },
+                    );
+                }
+            }
+        }
+        day = match day.succ_opt() {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    ret
+}
+fn to_rows(idx: usize, key: &SeriesKey, values: Series) -> impl Iterator<Item = OutputDataRow> {
+    let key = key.clone();
+    values.into_iter().map(move |(day, value)| OutputDataRow {
+        value,
+        start_date: day,
+        end_date: day,
+        metric_index: idx,
+        marketing_node: key.0.clone(),
+        ad_platform: key.1.clone(),
+        property_attributes: key.2.clone(),
+        node_details: None,
+    })
+}
+/// Applies the transform to daily rows
+pub fn transform_rows(
+    idx: usize,
+    transform: CoreTransform,
+    rows: OutputDataVec,
+    query: &QuasrQuery,
+) -> OutputDataVec {
+    into_series(rows)
+        .iter()
+        .flat_map(|(key, values)| {
+            to_rows(
+                idx,
+                key,
+                transform_series(values, transform, query.start_date, query.end_date),
+            )
+        })
+        .collect()
+}
+/// Applies the transform to a ratio. The numerators and denominators are transformed separately,
+/// so a rolling average of a ratio is the ratio of the rolling sums.
+pub fn transform_ratio(
+    idx: usize,
+    transform: CoreTransform,
+    numerators: OutputDataVec,
+    denominators: OutputDataVec,
+    query: &QuasrQuery,
+) -> OutputDataVec {
+    let transform = match transform {
+        CoreTransform::RollingAverage { window } => CoreTransform::RollingSum { window },
+        t => t,
+    };
+    let numerators = into_series(numerators);
+    let denominators = into_series(denominators);
+    let keys: HashSet<&SeriesKey> = numerators.keys().chain(denominators.keys()).collect();
+    let empty = Series::new();
+    keys.into_iter()
+        .flat_map(|key| {
+            let num = transform_series(
+                numerators.get(key).unwrap_or(&empty),
+                transform,
+                query.start_date,
+                query.end_date,
+            );
+            let den = transform_series(
+                denominators.get(key).unwrap_or(&empty),
+                transform,
+                query.start_date,
+                query.end_date,
+            );
+            let ratios: Series = num
+                .keys()
+                .chain(den.keys())
+                .map(|day| {
+                    (
+                        *day,
+                        do_qs_divide(*num.get(day).unwrap_or(&0.0), *den.get(day).unwrap_or(&0.0)),
+                    )
+                })
+                .collect();
+            to_rows(idx, key, ratios)
+        })
+        .collect()
+}
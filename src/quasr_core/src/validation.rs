@@ -0,0 +1,76 @@
+use crate::{input::QuasrQuery, MetricName};
+use serde::Serialize;
+use std::collections::HashSet;
+
+const MAX_SUGGESTIONS: usize = 3;
+
+/// A metric the query asks for that the organisation doesn't have
+#[derive(Debug, Serialize, Eq, PartialEq)]
+pub struct UnknownMetric {
+    pub name: MetricName,
+    /// Known metrics with a similar name, closest first
+    pub suggestions: Vec<MetricName>,
+}
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+fn suggestions_for(name: &str, known: &HashSet<MetricName>) -> Vec<MetricName> {
+    let lowercase_name = name.to_lowercase();
+    // Allow roughly one typo every three characters
+    let max_distance = (name.chars().count() / 3).max(2);
+    let mut candidates: Vec<(usize, &MetricName)> = known
+        .iter()
+        .map(|k| (levenshtein(&lowercase_name, &k.to_lowercase()), k))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, k)| k.clone())
+        .collect()
+}
+/// Base metrics of the query that are not in `known`, sorted by name
+pub fn find_unknown_metrics(query: &QuasrQuery, known: &HashSet<MetricName>) -> Vec<UnknownMetric> {
+    let mut unknown: Vec<UnknownMetric> = query
+        .base_metric_names()
+        .into_iter()
+        .filter(|name| !known.contains(name))
+        .map(|name| UnknownMetric {
+            suggestions: suggestions_for(&name, known),
+            name,
+        })
+        .collect();
+    unknown.sort_by(|a, b| a.name.cmp(&b.name));
+    unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{levenshtein, suggestions_for};
+    use crate::set;
+    use std::collections::HashSet;
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("Instal", "Install"), 1);
+        assert_eq!(levenshtein("", "Cost"), 4);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+    }
+    #[test]
+    fn test_suggestions() {
+        let known = set!["Install", "Installs (7d)", "Cost", "Clicks"];
+        assert_eq!(suggestions_for("Instal", &known), vec!["Install"]);
+        assert_eq!(suggestions_for("cost", &known), vec!["Cost"]);
+        assert!(suggestions_for("Revenue", &known).is_empty());
+    }
+}
@@ -0,0 +1,43 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+/// Source of the current time, so that relative dates can be resolved deterministically in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+    /// The current day in the given timezone
+    fn today(&self, timezone: Tz) -> NaiveDate {
+        self.now().with_timezone(&timezone).naive_local().date()
+    }
+}
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+/// Always returns the same time
+pub struct FixedClock(pub DateTime<Utc>);
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, FixedClock};
+    use chrono::{NaiveDate, TimeZone, Utc};
+    #[test]
+    fn test_today_in_timezone() {
+        let clock = FixedClock(Utc.ymd(2020, 6, 1).and_hms(23, 30, 0));
+        assert_eq!(clock.today(chrono_tz::UTC), NaiveDate::from_ymd(2020, 6, 1));
+        assert_eq!(
+            clock.today(chrono_tz::Europe::Madrid),
+            NaiveDate::from_ymd(2020, 6, 2)
+        );
+        assert_eq!(
+            clock.today(chrono_tz::America::Los_Angeles),
+            NaiveDate::from_ymd(2020, 6, 1)
+        );
+    }
+}
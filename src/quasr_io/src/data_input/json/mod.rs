@@ -1,11 +1,19 @@
+mod relative_dates;
+
 use super::super::date_format;
 use chrono::NaiveDate;
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 use quasr_core::{
-    input::{CoreMarketingNodeFilter, CoreMarketingNodeLevel, CoreTimeBreakdown, QuasrQuery},
+    calendar::CoreCalendar,
+    input::{
+        CoreAttribution, CoreMarketingNodeFilter, CoreMarketingNodeLevel, CorePropertyAttribute,
+        CorePropertyAttributeFilter, CoreTimeBreakdown, CoreTransform, QuasrQuery,
+        MAX_ROLLING_WINDOW,
+    },
     set, CoreMetric,
 };
-use std::collections::HashSet;
+pub use relative_dates::RelativeDateRange;
+use std::collections::{BTreeMap, HashSet};
 
 #[derive(Deserialize, Eq, PartialEq, Hash, Copy, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -28,14 +36,41 @@ impl Default for MarketingNodeLevel {
         MarketingNodeLevel::Ad
     }
 }
+#[derive(Deserialize, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum PropertyAttribute {
+    Objective,
+    PropertyStatus,
+    DisplayStatus,
+    BidStrategy,
+    PropertyType,
+}
+impl From<PropertyAttribute> for CorePropertyAttribute {
+    fn from(attribute: PropertyAttribute) -> Self {
+        match attribute {
+            PropertyAttribute::Objective => CorePropertyAttribute::Objective,
+            PropertyAttribute::PropertyStatus => CorePropertyAttribute::PropertyStatus,
+            PropertyAttribute::DisplayStatus => CorePropertyAttribute::DisplayStatus,
+            PropertyAttribute::BidStrategy => CorePropertyAttribute::BidStrategy,
+            PropertyAttribute::PropertyType => CorePropertyAttribute::PropertyType,
+        }
+    }
+}
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct TimeRange {
+struct AbsoluteTimeRange {
     #[serde(with = "date_format")]
     start_date: NaiveDate,
     #[serde(with = "date_format")]
     end_date: NaiveDate,
 }
+/// Either `{"startDate": "2020-01-01", "endDate": "2020-01-31"}` or e.g. `"last_7_days"`
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum TimeRange {
+    Absolute(AbsoluteTimeRange),
+    Relative(RelativeDateRange),
+}
 #[derive(Deserialize, Serialize)]
 struct TimeValue {
     value: TimeRange,
@@ -45,6 +80,11 @@ struct MarketingNodeFilter {
     value: String,
     level: MarketingNodeLevel,
 }
+#[derive(Deserialize, Serialize)]
+struct PropertyAttributeFilter {
+    value: String,
+    attribute: PropertyAttribute,
+}
 
 // #[derive(Deserialize)]
 // struct AdPlatformValue {
@@ -56,6 +96,7 @@ struct MarketingNodeFilter {
 struct ConditionSet {
     time: Vec<TimeValue>,
     marketing_node: Option<Vec<MarketingNodeFilter>>,
+    property_attribute: Option<Vec<PropertyAttributeFilter>>,
     // ad_platform: Option<Vec<AdPlatformValue>>,
 }
 impl ConditionSet {
@@ -76,16 +117,39 @@ impl ConditionSet {
         }
         Ok(None)
     }
+    /// Values for the same attribute are OR'ed together, different attributes are AND'ed
+    fn get_property_attribute_filter(&self) -> Vec<CorePropertyAttributeFilter> {
+        let mut by_attribute: BTreeMap<PropertyAttribute, Vec<String>> = BTreeMap::new();
+        if let Some(filters) = &self.property_attribute {
+            filters.iter().for_each(|f| {
+                by_attribute
+                    .entry(f.attribute)
+                    .or_default()
+                    .push(f.value.clone())
+            });
+        }
+        by_attribute
+            .into_iter()
+            .map(|(attribute, value)| CorePropertyAttributeFilter {
+                attribute: attribute.into(),
+                value,
+            })
+            .collect()
+    }
 }
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 enum TimeBreakdown {
     Daily,
+    Weekly,
+    Monthly,
 }
 impl Into<CoreTimeBreakdown> for TimeBreakdown {
     fn into(self) -> CoreTimeBreakdown {
         match self {
             Self::Daily => CoreTimeBreakdown::Day,
+            Self::Weekly => CoreTimeBreakdown::Week,
+            Self::Monthly => CoreTimeBreakdown::Month,
         }
     }
 }
@@ -96,11 +160,28 @@ struct BreakdownSet {
     time: Option<TimeBreakdown>,
     marketing_node: Option<MarketingNodeLevel>,
     ad_platform: Option<String>,
+    property_attribute: Option<Vec<PropertyAttribute>>,
+}
+/// Attribution settings of a lower funnel metric, e.g. `{"mode": "click", "window": 7}`
+#[derive(Deserialize, Serialize, Clone, Eq, PartialEq, Hash)]
+struct Attribution {
+    mode: String,
+    window: i32,
+}
+impl From<Attribution> for CoreAttribution {
+    fn from(attribution: Attribution) -> Self {
+        CoreAttribution {
+            mode: attribution.mode,
+            window: attribution.window,
+        }
+    }
 }
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ConcreteMetric {
     metric_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attribution: Option<Attribution>,
 }
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -109,6 +190,8 @@ enum SummationOrUpperFunnel {
     #[serde(rename_all = "camelCase")]
     UpperFunnelMetric {
         metric_name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attribution: Option<Attribution>,
     },
     SummationMetric {
         metrics: Vec<ConcreteMetric>,
@@ -117,12 +200,21 @@ enum SummationOrUpperFunnel {
 impl SummationOrUpperFunnel {
     fn into_hash_set(self) -> HashSet<String> {
         match self {
-            Self::UpperFunnelMetric { metric_name } => set![metric_name],
+            Self::UpperFunnelMetric { metric_name, .. } => set![metric_name],
             Self::SummationMetric { metrics } => {
                 metrics.into_iter().map(|m| m.metric_name).collect()
             }
         }
     }
+    fn attributions(&self) -> Vec<&Attribution> {
+        match self {
+            Self::UpperFunnelMetric { attribution, .. } => attribution.iter().collect(),
+            Self::SummationMetric { metrics } => metrics
+                .iter()
+                .filter_map(|m| m.attribution.as_ref())
+                .collect(),
+        }
+    }
 }
 #[allow(clippy::enum_variant_names)]
 #[derive(Deserialize, Serialize)]
@@ -130,7 +222,11 @@ impl SummationOrUpperFunnel {
 #[serde(tag = "metricType")]
 enum Metric {
     #[serde(rename_all = "camelCase")]
-    UpperFunnelMetric { metric_name: String },
+    UpperFunnelMetric {
+        metric_name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attribution: Option<Attribution>,
+    },
     #[serde(rename_all = "camelCase")]
     SummationMetric {
         // metric_name: Option<String>,
@@ -144,23 +240,111 @@ enum Metric {
         denominator: SummationOrUpperFunnel,
     },
 }
-impl Into<CoreMetric> for Metric {
-    fn into(self) -> CoreMetric {
+impl Metric {
+    fn attributions(&self) -> Vec<&Attribution> {
         match self {
-            Self::UpperFunnelMetric { metric_name } => CoreMetric::UpperFunnelMetric(metric_name),
-            Self::SummationMetric { metrics, .. } => {
+            Self::UpperFunnelMetric { attribution, .. } => attribution.iter().collect(),
+            Self::SummationMetric { metrics } => metrics
+                .iter()
+                .filter_map(|m| m.attribution.as_ref())
+                .collect(),
+            Self::DivisionMetric {
+                numerator,
+                denominator,
+            } => numerator
+                .attributions()
+                .into_iter()
+                .chain(denominator.attributions())
+                .collect(),
+        }
+    }
+}
+impl TryFrom<Metric> for CoreMetric {
+    type Error = SimpleError;
+    fn try_from(metric: Metric) -> BoxResult<Self> {
+        // Metrics without an attribution take the one of the rest of the metric
+        let attributions = metric
+            .attributions()
+            .into_iter()
+            .cloned()
+            .collect::<HashSet<Attribution>>();
+        if attributions.len() > 1 {
+            bail!("A metric can't mix different attribution settings!");
+        }
+        let core_metric = match metric {
+            Metric::UpperFunnelMetric { metric_name, .. } => {
+                CoreMetric::UpperFunnelMetric(metric_name)
+            }
+            Metric::SummationMetric { metrics, .. } => {
                 CoreMetric::SummationMetric(metrics.into_iter().map(|i| i.metric_name).collect())
             }
-            Self::DivisionMetric {
+            Metric::DivisionMetric {
                 numerator,
                 denominator,
             } => CoreMetric::DivisionMetric {
                 numerator: numerator.into_hash_set(),
                 denominator: denominator.into_hash_set(),
             },
+        };
+        Ok(match attributions.into_iter().next() {
+            Some(attribution) => CoreMetric::AttributedMetric {
+                attribution: attribution.into(),
+                metric: Box::new(core_metric),
+            },
+            None => core_metric,
+        })
+    }
+}
+/// e.g. `{"type": "rollingAverage", "window": 7}` or `{"type": "cumulative"}`
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "type")]
+enum Transform {
+    RollingSum { window: u32 },
+    RollingAverage { window: u32 },
+    Cumulative,
+}
+impl From<Transform> for CoreTransform {
+    fn from(transform: Transform) -> Self {
+        match transform {
+            Transform::RollingSum { window } => CoreTransform::RollingSum { window },
+            Transform::RollingAverage { window } => CoreTransform::RollingAverage { window },
+            Transform::Cumulative => CoreTransform::Cumulative,
         }
     }
 }
+#[derive(Deserialize, Serialize)]
+struct TransformableMetric {
+    #[serde(flatten)]
+    metric: Metric,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transform: Option<Transform>,
+}
+impl TryFrom<TransformableMetric> for CoreMetric {
+    type Error = SimpleError;
+    fn try_from(metric: TransformableMetric) -> BoxResult<Self> {
+        let core_metric = CoreMetric::try_from(metric.metric)?;
+        Ok(match metric.transform {
+            Some(Transform::RollingSum { window: 0 })
+            | Some(Transform::RollingAverage { window: 0 }) => {
+                bail!("Rolling windows have to be at least one day long!")
+            }
+            Some(Transform::RollingSum { window }) | Some(Transform::RollingAverage { window })
+                if window > MAX_ROLLING_WINDOW =>
+            {
+                bail!(
+                    "Rolling windows can be at most {} days long!",
+                    MAX_ROLLING_WINDOW
+                )
+            }
+            Some(transform) => CoreMetric::TransformedMetric {
+                transform: transform.into(),
+                metric: Box::new(core_metric),
+            },
+            None => core_metric,
+        })
+    }
+}
 
 use serde::{Deserialize, Serialize};
 #[derive(Deserialize, Serialize)]
@@ -170,11 +354,51 @@ pub struct AdsFlowQuery {
     org_id: String,
 }
 #[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 struct DataQuery {
-    metrics: Vec<Metric>,
+    metrics: Vec<TransformableMetric>,
     filters: ConditionSet,
     #[serde(default)]
     breakdowns: BreakdownSet,
+    #[serde(default)]
+    include_metadata: bool,
+}
+impl AdsFlowQuery {
+    pub fn org_id(&self) -> &str {
+        &self.org_id
+    }
+    /// Turns relative date ranges into absolute ones, relative to `today`
+    pub fn resolve_dates(&mut self, today: NaiveDate) {
+        for time in self.data_query.filters.time.iter_mut() {
+            if let TimeRange::Relative(range) = time.value {
+                let (start_date, end_date) = range.resolve(today);
+                time.value = TimeRange::Absolute(AbsoluteTimeRange {
+                    start_date,
+                    end_date,
+                });
+            }
+        }
+    }
+    /// Replaces the start and/or end date of the time filter. Relative ranges have to be
+    /// resolved first, they are left as they are.
+    pub fn override_dates(&mut self, start_date: Option<NaiveDate>, end_date: Option<NaiveDate>) {
+        for time in self.data_query.filters.time.iter_mut() {
+            if let TimeRange::Absolute(range) = &mut time.value {
+                if let Some(start_date) = start_date {
+                    range.start_date = start_date;
+                }
+                if let Some(end_date) = end_date {
+                    range.end_date = end_date;
+                }
+            }
+        }
+    }
+}
+/// One query of a batch, with the id its results are returned under
+#[derive(Deserialize, Serialize)]
+pub struct BatchQuery {
+    pub id: String,
+    pub query: AdsFlowQuery,
 }
 type BoxResult<T> = Result<T, SimpleError>;
 use simple_error::{bail, SimpleError};
@@ -184,24 +408,48 @@ impl TryInto<QuasrQuery> for AdsFlowQuery {
         if self.data_query.filters.time.len() != 1 {
             bail!("You can only have one time filter!")
         }
+        let (start_date, end_date) = match &self.data_query.filters.time[0].value {
+            TimeRange::Absolute(range) => (range.start_date, range.end_date),
+            TimeRange::Relative(_) => bail!("Relative date ranges have to be resolved first!"),
+        };
+        let metrics = self
+            .data_query
+            .metrics
+            .into_iter()
+            .map(CoreMetric::try_from)
+            .collect::<BoxResult<Vec<CoreMetric>>>()?;
+        if metrics
+            .iter()
+            .any(|m| matches!(m, CoreMetric::TransformedMetric { .. }))
+            && !matches!(self.data_query.breakdowns.time, Some(TimeBreakdown::Daily))
+        {
+            bail!("Transformed metrics need a daily time breakdown!")
+        }
+        let ad_platform_breakdown = match self.data_query.breakdowns.ad_platform {
+            Some(e) if e == "adPlatform" => true,
+            None => false,
+            Some(e) => bail!("{} is not a valid ad platform", e),
+        };
         Ok(QuasrQuery {
             org_id: self.org_id,
-            marketing_node_filter: self.data_query.filters.get_marketing_node_filter().unwrap(),
-            start_date: self.data_query.filters.time[0].value.start_date,
-            end_date: self.data_query.filters.time[0].value.end_date,
+            marketing_node_filter: self.data_query.filters.get_marketing_node_filter()?,
+            start_date,
+            end_date,
             marketing_node_breakdown: self.data_query.breakdowns.marketing_node.map(|m| m.into()),
-            ad_platform_breakdown: match self.data_query.breakdowns.ad_platform {
-                Some(e) if e == "adPlatform" => true,
-                None => false,
-                Some(e) => panic!("{} is not a valid ad platform", e),
-            },
+            ad_platform_breakdown,
             time_breakdown: self.data_query.breakdowns.time.map(|m| m.into()),
-            metrics: self
+            property_attribute_filter: self.data_query.filters.get_property_attribute_filter(),
+            include_metadata: self.data_query.include_metadata,
+            calendar: CoreCalendar::default(),
+            property_attribute_breakdown: self
                 .data_query
-                .metrics
+                .breakdowns
+                .property_attribute
+                .unwrap_or_default()
                 .into_iter()
-                .map(|i| i.into())
+                .map(|a| a.into())
                 .collect(),
+            metrics,
         })
     }
     type Error = SimpleError;
@@ -209,7 +457,10 @@ impl TryInto<QuasrQuery> for AdsFlowQuery {
 
 #[cfg(test)]
 mod test {
-    use super::{set, AdsFlowQuery, CoreMetric, QuasrQuery};
+    use super::{
+        set, AdsFlowQuery, CoreAttribution, CoreMetric, CorePropertyAttribute, CoreTransform,
+        NaiveDate, QuasrQuery,
+    };
     use serde_json;
     use std::{collections::HashSet, convert::TryInto};
     #[test]
@@ -225,4 +476,112 @@ mod test {
             }]
         )
     }
+    #[test]
+    fn test_override_dates() {
+        let mut json: AdsFlowQuery = serde_json::from_str(include_str!("data/query.json")).unwrap();
+        json.override_dates(None, Some(NaiveDate::from_ymd(2018, 10, 1)));
+        let core_query: QuasrQuery = json.try_into().unwrap();
+        assert_eq!(core_query.start_date, NaiveDate::from_ymd(2018, 9, 18));
+        assert_eq!(core_query.end_date, NaiveDate::from_ymd(2018, 10, 1));
+    }
+    #[test]
+    fn test_relative_dates() {
+        let mut json: AdsFlowQuery =
+            serde_json::from_str(&include_str!("data/query.json").replace(
+                r#"{
+            "startDate": "2018-09-18",
+            "endDate": "2018-09-25"
+          }"#,
+                r#""previous_month""#,
+            ))
+            .unwrap();
+        json.resolve_dates(NaiveDate::from_ymd(2020, 3, 15));
+        let core_query: QuasrQuery = json.try_into().unwrap();
+        assert_eq!(core_query.start_date, NaiveDate::from_ymd(2020, 2, 1));
+        assert_eq!(core_query.end_date, NaiveDate::from_ymd(2020, 2, 29));
+    }
+    #[test]
+    fn test_transform() {
+        let json: AdsFlowQuery =
+            serde_json::from_str(include_str!("data/query_transform.json")).unwrap();
+        let core_query: QuasrQuery = json.try_into().unwrap();
+        assert_eq!(
+            core_query.metrics[1],
+            CoreMetric::TransformedMetric {
+                transform: CoreTransform::RollingAverage { window: 7 },
+                metric: Box::new(CoreMetric::DivisionMetric {
+                    numerator: set!["Cost"],
+                    denominator: set!["Install"]
+                })
+            }
+        );
+        assert_eq!(core_query.lookback_days(), 6);
+
+        let json: AdsFlowQuery = serde_json::from_str(
+            &include_str!("data/query_transform.json").replace("daily", "weekly"),
+        )
+        .unwrap();
+        assert!(TryInto::<QuasrQuery>::try_into(json).is_err());
+
+        let json: AdsFlowQuery = serde_json::from_str(
+            &include_str!("data/query_transform.json")
+                .replace("\"window\": 7", "\"window\": 4000000000"),
+        )
+        .unwrap();
+        assert!(TryInto::<QuasrQuery>::try_into(json).is_err());
+    }
+    #[test]
+    fn test_deserialize_property_attributes() {
+        let json: AdsFlowQuery =
+            serde_json::from_str(include_str!("data/query_property_attributes.json")).unwrap();
+        let core_query: QuasrQuery = json.try_into().unwrap();
+        assert_eq!(
+            core_query.property_attribute_breakdown,
+            vec![
+                CorePropertyAttribute::Objective,
+                CorePropertyAttribute::BidStrategy
+            ]
+        );
+        assert_eq!(core_query.property_attribute_filter.len(), 1);
+        assert_eq!(
+            core_query.property_attribute_filter[0].attribute,
+            CorePropertyAttribute::PropertyStatus
+        );
+        assert_eq!(
+            core_query.property_attribute_filter[0].value,
+            vec!["ACTIVE".to_owned(), "PAUSED".to_owned()]
+        );
+    }
+    #[test]
+    fn test_deserialize_attribution() {
+        let json: AdsFlowQuery =
+            serde_json::from_str(include_str!("data/query_attribution.json")).unwrap();
+        let core_query: QuasrQuery = json.try_into().unwrap();
+        assert_eq!(
+            core_query.metrics,
+            vec![
+                CoreMetric::UpperFunnelMetric("Cost".to_owned()),
+                CoreMetric::AttributedMetric {
+                    attribution: CoreAttribution {
+                        mode: "click".to_owned(),
+                        window: 7
+                    },
+                    metric: Box::new(CoreMetric::DivisionMetric {
+                        numerator: set!["Cost"],
+                        denominator: set!["Install", "Other Install"]
+                    })
+                }
+            ]
+        )
+    }
+    #[test]
+    fn test_mixed_attribution_is_rejected() {
+        let json = include_str!("data/query_attribution.json").replace(
+            "\"metricName\": \"Other Install\"",
+            "\"metricName\": \"Other Install\", \"attribution\": { \"mode\": \"view\", \"window\": 1 }",
+        );
+        let json: AdsFlowQuery = serde_json::from_str(&json).unwrap();
+        let core_query: Result<QuasrQuery, _> = json.try_into();
+        assert!(core_query.is_err());
+    }
 }
@@ -0,0 +1,73 @@
+use chrono::{Datelike, Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+/// A date range relative to the day the query is answered on. Apart from the "to date" ranges
+/// they end yesterday, as today's data is still incomplete.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RelativeDateRange {
+    #[serde(rename = "yesterday")]
+    Yesterday,
+    #[serde(rename = "last_7_days")]
+    Last7Days,
+    #[serde(rename = "last_30_days")]
+    Last30Days,
+    #[serde(rename = "month_to_date")]
+    MonthToDate,
+    #[serde(rename = "previous_month")]
+    PreviousMonth,
+    #[serde(rename = "year_to_date")]
+    YearToDate,
+}
+impl RelativeDateRange {
+    /// Start and end date of the range, both included
+    pub fn resolve(self, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let yesterday = today - Duration::days(1);
+        let month_start = today.with_day(1).unwrap();
+        match self {
+            Self::Yesterday => (yesterday, yesterday),
+            Self::Last7Days => (today - Duration::days(7), yesterday),
+            Self::Last30Days => (today - Duration::days(30), yesterday),
+            Self::MonthToDate => (month_start, today),
+            Self::PreviousMonth => {
+                let previous_month_end = month_start - Duration::days(1);
+                (previous_month_end.with_day(1).unwrap(), previous_month_end)
+            }
+            Self::YearToDate => (NaiveDate::from_ymd_opt(today.year(), 1, 1).unwrap(), today),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RelativeDateRange;
+    use chrono::NaiveDate;
+    #[test]
+    fn test_resolve() {
+        let today = NaiveDate::from_ymd(2020, 3, 1);
+        let d = NaiveDate::from_ymd;
+        assert_eq!(
+            RelativeDateRange::Yesterday.resolve(today),
+            (d(2020, 2, 29), d(2020, 2, 29))
+        );
+        assert_eq!(
+            RelativeDateRange::Last7Days.resolve(today),
+            (d(2020, 2, 23), d(2020, 2, 29))
+        );
+        assert_eq!(
+            RelativeDateRange::Last30Days.resolve(today),
+            (d(2020, 1, 31), d(2020, 2, 29))
+        );
+        assert_eq!(
+            RelativeDateRange::MonthToDate.resolve(today),
+            (d(2020, 3, 1), d(2020, 3, 1))
+        );
+        assert_eq!(
+            RelativeDateRange::PreviousMonth.resolve(today),
+            (d(2020, 2, 1), d(2020, 2, 29))
+        );
+        assert_eq!(
+            RelativeDateRange::YearToDate.resolve(today),
+            (d(2020, 1, 1), d(2020, 3, 1))
+        );
+    }
+}
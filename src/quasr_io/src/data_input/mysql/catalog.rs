@@ -0,0 +1,102 @@
+use chrono::NaiveDate;
+use diesel::{
+    dsl::sql_query,
+    prelude::*,
+    sql_types::{Bool, Char, Date, Integer, Nullable, Varchar},
+    QueryableByName,
+};
+use quasr_core::schema::SchemaMapping;
+use serde::Serialize;
+
+/// A metric an organisation has data for, as described by `UpperFunnelMetricFields`
+#[derive(Debug, Serialize, QueryableByName)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricField {
+    #[sql_type = "Varchar"]
+    pub name: String,
+    #[sql_type = "Bool"]
+    #[column_name = "hasCurrency"]
+    pub has_currency: bool,
+    #[sql_type = "Nullable<Bool>"]
+    #[column_name = "isLowerFunnel"]
+    pub is_lower_funnel: Option<bool>,
+    #[sql_type = "Nullable<Varchar>"]
+    #[column_name = "calculationMode"]
+    pub calculation_mode: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    #[column_name = "attributionMode"]
+    pub attribution_mode: Option<String>,
+    #[sql_type = "Nullable<Integer>"]
+    #[column_name = "attributionWindow"]
+    pub attribution_window: Option<i32>,
+    #[sql_type = "Nullable<Varchar>"]
+    #[column_name = "lowerFunnelMetricName"]
+    pub lower_funnel_metric_name: Option<String>,
+    /// First and last day with values for the metric, `None` if there are none
+    #[sql_type = "Nullable<Date>"]
+    #[column_name = "firstDate"]
+    pub first_date: Option<NaiveDate>,
+    #[sql_type = "Nullable<Date>"]
+    #[column_name = "lastDate"]
+    pub last_date: Option<NaiveDate>,
+}
+
+/// Name of one of the organisation's metrics, without the dates `MetricField` has
+#[derive(Debug, QueryableByName)]
+pub struct KnownMetric {
+    #[sql_type = "Varchar"]
+    pub name: String,
+    #[sql_type = "Bool"]
+    #[column_name = "hasCurrency"]
+    pub has_currency: bool,
+}
+
+/// The organisation's metric names, from the fields table alone. Cheaper than
+/// `load_metric_catalog`, which scans the values for their dates.
+pub fn load_known_metrics(
+    con: &MysqlConnection,
+    org_id: &str,
+    schema: &SchemaMapping,
+) -> QueryResult<Vec<KnownMetric>> {
+    let f = &schema.fields;
+    sql_query(format!(
+        "SELECT DISTINCT {} AS name,{} AS hasCurrency FROM {} WHERE {} = ?",
+        f.col(&f.name),
+        f.col(&f.has_currency),
+        schema.qualify(&f.table),
+        f.col(&f.organization_id),
+    ))
+    .bind::<Char, _>(org_id)
+    .load(con)
+}
+pub fn load_metric_catalog(
+    con: &MysqlConnection,
+    org_id: &str,
+    schema: &SchemaMapping,
+) -> QueryResult<Vec<MetricField>> {
+    let (f, v) = (&schema.fields, &schema.values);
+    sql_query(format!(
+        "SELECT {} AS name,{} AS hasCurrency,{} AS isLowerFunnel,{} AS calculationMode,\
+         {} AS attributionMode,{} AS attributionWindow,{} AS lowerFunnelMetricName,\
+         MIN({date}) AS firstDate,MAX({date}) AS lastDate \
+         FROM {} LEFT JOIN {} ON {}={} \
+         WHERE {} = ? GROUP BY {} ORDER BY {}",
+        f.col(&f.name),
+        f.col(&f.has_currency),
+        f.col(&f.is_lower_funnel),
+        f.col(&f.calculation_mode),
+        f.col(&f.attribution_mode),
+        f.col(&f.attribution_window),
+        f.col(&f.lower_funnel_metric_name),
+        schema.qualify(&f.table),
+        schema.qualify(&v.table),
+        f.col(&f.id),
+        v.col(&v.field_id),
+        f.col(&f.organization_id),
+        f.col(&f.id),
+        f.col(&f.name),
+        date = v.col(&v.date),
+    ))
+    .bind::<Char, _>(org_id)
+    .load(con)
+}
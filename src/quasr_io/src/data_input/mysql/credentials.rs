@@ -0,0 +1,35 @@
+use diesel::{dsl::sql_query, prelude::*, sql_types::Char, QueryableByName};
+use sha2::{Digest, Sha256};
+
+#[allow(non_snake_case)]
+#[derive(Debug, QueryableByName)]
+struct DbApiKeyRow {
+    #[sql_type = "Char"]
+    pub organizationId: String,
+}
+
+/// API keys are stored as the hex encoded SHA-256 of the key
+pub fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Organisations that a (non revoked) API key may query
+pub fn load_org_ids_for_api_key(con: &MysqlConnection, key_hash: &str) -> QueryResult<Vec<String>> {
+    let db_rows: Vec<DbApiKeyRow> =
+        sql_query("SELECT organizationId FROM ApiKeys WHERE keyHash = ? AND revokedAt IS NULL")
+            .bind::<Char, _>(key_hash)
+            .load(con)?;
+    Ok(db_rows.into_iter().map(|r| r.organizationId).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::hash_api_key;
+    #[test]
+    fn test_hash_api_key() {
+        assert_eq!(
+            hash_api_key("secret"),
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use chrono::NaiveDate;
+use diesel::{
+    dsl::sql_query,
+    prelude::*,
+    sql_types::{Char, Date, Nullable, Varchar},
+    QueryableByName,
+};
+use quasr_core::{
+    build_node_details_sql, input::MarketingNodeDetails, schema::SchemaMapping, MarketingNode,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::{load_node_details_from_db, QueryError};
+
+/// A property that has values for the organisation
+#[allow(non_snake_case)]
+#[derive(Debug, QueryableByName)]
+pub struct DbPropertyRow {
+    #[sql_type = "Char"]
+    pub id: String,
+    #[sql_type = "Varchar"]
+    pub name: String,
+    #[sql_type = "Nullable<Varchar>"]
+    pub accountId: Option<String>,
+    #[sql_type = "Nullable<Char>"]
+    pub campaignId: Option<String>,
+    #[sql_type = "Nullable<Char>"]
+    pub adSetId: Option<String>,
+    #[sql_type = "Nullable<Char>"]
+    pub adId: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub propertyStatus: Option<String>,
+    #[sql_type = "Nullable<Date>"]
+    pub lastActivity: Option<NaiveDate>,
+}
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum HierarchyLevel {
+    Campaign,
+    AdSet,
+    Ad,
+}
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HierarchyNode {
+    pub id: MarketingNode,
+    pub level: HierarchyLevel,
+    pub name: Option<String>,
+    pub status: Option<String>,
+    pub account_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<NaiveDate>,
+    pub children: Vec<HierarchyNode>,
+}
+/// Nodes are kept if they match every filter themselves, or if any of their children is kept
+#[derive(Debug, Default)]
+pub struct HierarchyFilter {
+    pub platform: Option<String>,
+    pub status: Option<String>,
+    pub name_prefix: Option<String>,
+}
+impl HierarchyFilter {
+    fn matches(&self, name: Option<&String>, status: Option<&String>) -> bool {
+        let status_matches = match &self.status {
+            Some(wanted) => matches!(status, Some(s) if s.eq_ignore_ascii_case(wanted)),
+            None => true,
+        };
+        let name_matches = match &self.name_prefix {
+            Some(prefix) => matches!(
+                name,
+                Some(n) if n.to_lowercase().starts_with(&prefix.to_lowercase())
+            ),
+            None => true,
+        };
+        status_matches && name_matches
+    }
+}
+
+pub fn load_active_properties(
+    con: &MysqlConnection,
+    org_id: &str,
+    platform: Option<&str>,
+    with_last_activity: bool,
+    schema: &SchemaMapping,
+) -> QueryResult<Vec<DbPropertyRow>> {
+    let (v, f, p) = (&schema.values, &schema.fields, &schema.properties);
+    let last_activity = if with_last_activity {
+        format!("MAX({})", v.col(&v.date))
+    } else {
+        "NULL".to_owned()
+    };
+    let platform_filter = if platform.is_some() {
+        format!("AND {} = ? ", v.col(&v.ad_platform))
+    } else {
+        "".to_owned()
+    };
+    let query = sql_query(format!(
+        "SELECT {} AS id,{} AS name,{} AS accountId,{} AS campaignId,{} AS adSetId,{} AS adId,\
+         {} AS propertyStatus,{} AS lastActivity \
+         FROM {},{},{} \
+         WHERE {} = ? AND {}={} AND {}={} {}\
+         GROUP BY {}",
+        p.col(&p.id),
+        p.col(&p.name),
+        p.col(&p.account_id),
+        p.col(&p.campaign_id),
+        p.col(&p.ad_set_id),
+        p.col(&p.ad_id),
+        p.col(&p.property_status),
+        last_activity,
+        schema.qualify(&v.table),
+        schema.qualify(&f.table),
+        schema.qualify(&p.table),
+        f.col(&f.organization_id),
+        f.col(&f.id),
+        v.col(&v.field_id),
+        p.col(&p.id),
+        v.col(&v.property_id),
+        platform_filter,
+        p.col(&p.id),
+    ))
+    .bind::<Char, _>(org_id);
+    match platform {
+        Some(platform) => query.bind::<Varchar, _>(platform).load(con),
+        None => query.load(con),
+    }
+}
+
+struct NodeInfo {
+    name: Option<String>,
+    status: Option<String>,
+    account_id: Option<String>,
+    last_activity: Option<NaiveDate>,
+}
+/// Campaign, ad set and ad ids of a property, as far down as they go
+fn property_path(row: &DbPropertyRow) -> Vec<(HierarchyLevel, &MarketingNode)> {
+    [
+        (HierarchyLevel::Campaign, &row.campaignId),
+        (HierarchyLevel::AdSet, &row.adSetId),
+        (HierarchyLevel::Ad, &row.adId),
+    ]
+    .iter()
+    .take_while(|(_, id)| id.is_some())
+    .map(|(level, id)| (*level, id.as_ref().unwrap()))
+    .collect()
+}
+/// Ids of the campaigns and ad sets of `rows` that don't have values themselves
+pub fn parent_ids_without_rows(rows: &[DbPropertyRow]) -> HashSet<MarketingNode> {
+    let own_ids: HashSet<&MarketingNode> = rows.iter().map(|r| &r.id).collect();
+    rows.iter()
+        .flat_map(property_path)
+        .map(|(_, id)| id)
+        .filter(|id| !own_ids.contains(id))
+        .cloned()
+        .collect()
+}
+#[derive(Default)]
+struct Tree(BTreeMap<MarketingNode, (HierarchyLevel, Tree)>);
+impl Tree {
+    fn insert(&mut self, path: &[(HierarchyLevel, &MarketingNode)]) {
+        if let Some(((level, id), rest)) = path.split_first() {
+            self.0
+                .entry((*id).clone())
+                .or_insert_with(|| (*level, Tree::default()))
+                .1
+                .insert(rest);
+        }
+    }
+    fn into_nodes(
+        self,
+        info: &HashMap<MarketingNode, NodeInfo>,
+        filter: &HierarchyFilter,
+    ) -> Vec<HierarchyNode> {
+        self.0
+            .into_iter()
+            .filter_map(|(id, (level, children))| {
+                let children = children.into_nodes(info, filter);
+                let node_info = info.get(&id);
+                let name = node_info.and_then(|i| i.name.clone());
+                let status = node_info.and_then(|i| i.status.clone());
+                if children.is_empty() && !filter.matches(name.as_ref(), status.as_ref()) {
+                    return None;
+                }
+                let last_activity = children
+                    .iter()
+                    .filter_map(|c| c.last_activity)
+                    .chain(node_info.and_then(|i| i.last_activity))
+                    .max();
+                let account_id = node_info
+                    .and_then(|i| i.account_id.clone())
+                    .or_else(|| children.iter().find_map(|c| c.account_id.clone()));
+                Some(HierarchyNode {
+                    id,
+                    level,
+                    name,
+                    status,
+                    account_id,
+                    last_activity,
+                    children,
+                })
+            })
+            .collect()
+    }
+}
+/// Builds the campaign -> ad set -> ad tree of `rows`. `parents` has the details of the
+/// campaigns and ad sets that don't have values of their own.
+pub fn build_hierarchy(
+    rows: &[DbPropertyRow],
+    parents: &HashMap<MarketingNode, MarketingNodeDetails>,
+    filter: &HierarchyFilter,
+) -> Vec<HierarchyNode> {
+    let mut tree = Tree::default();
+    rows.iter().for_each(|r| tree.insert(&property_path(r)));
+    let mut info: HashMap<MarketingNode, NodeInfo> = parents
+        .iter()
+        .map(|(id, details)| {
+            (
+                id.clone(),
+                NodeInfo {
+                    name: Some(details.name.clone()),
+                    status: details.property_status.clone(),
+                    account_id: None,
+                    last_activity: None,
+                },
+            )
+        })
+        .collect();
+    rows.iter().for_each(|r| {
+        info.insert(
+            r.id.clone(),
+            NodeInfo {
+                name: Some(r.name.clone()),
+                status: r.propertyStatus.clone(),
+                account_id: r.accountId.clone(),
+                last_activity: r.lastActivity,
+            },
+        );
+    });
+    tree.into_nodes(&info, filter)
+}
+pub fn load_hierarchy(
+    con: &MysqlConnection,
+    org_id: &str,
+    filter: &HierarchyFilter,
+    with_last_activity: bool,
+    schema: &SchemaMapping,
+) -> Result<Vec<HierarchyNode>, QueryError> {
+    let rows = load_active_properties(
+        con,
+        org_id,
+        filter.platform.as_deref(),
+        with_last_activity,
+        schema,
+    )?;
+    let parent_ids = parent_ids_without_rows(&rows);
+    let parents = if parent_ids.is_empty() {
+        HashMap::new()
+    } else {
+        load_node_details_from_db(con, build_node_details_sql(org_id, &parent_ids, schema))?
+    };
+    Ok(build_hierarchy(&rows, &parents, filter))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_hierarchy, parent_ids_without_rows, DbPropertyRow, HierarchyFilter};
+    use chrono::NaiveDate;
+    use quasr_core::input::MarketingNodeDetails;
+    use std::collections::HashMap;
+
+    fn ad(id: &str, name: &str, campaign: &str, ad_set: &str, day: u32) -> DbPropertyRow {
+        DbPropertyRow {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            accountId: Some("account".to_owned()),
+            campaignId: Some(campaign.to_owned()),
+            adSetId: Some(ad_set.to_owned()),
+            adId: Some(id.to_owned()),
+            propertyStatus: Some("ACTIVE".to_owned()),
+            lastActivity: Some(NaiveDate::from_ymd(2020, 1, day)),
+        }
+    }
+    fn details(name: &str, status: &str) -> MarketingNodeDetails {
+        MarketingNodeDetails {
+            name: name.to_owned(),
+            handle: None,
+            property_status: Some(status.to_owned()),
+            external_created_at: None,
+            campaign_id: None,
+            ad_set_id: None,
+            daily_budget: None,
+        }
+    }
+    #[test]
+    fn test_build_hierarchy() {
+        let rows = vec![
+            ad("ad1", "Ad one", "c1", "s1", 1),
+            ad("ad2", "Ad two", "c1", "s1", 3),
+            ad("ad3", "Other ad", "c2", "s2", 2),
+        ];
+        let mut parents = HashMap::new();
+        parents.insert("c1".to_owned(), details("Summer", "ACTIVE"));
+        parents.insert("s1".to_owned(), details("Summer set", "ACTIVE"));
+        parents.insert("c2".to_owned(), details("Winter", "PAUSED"));
+        parents.insert("s2".to_owned(), details("Winter set", "PAUSED"));
+        assert_eq!(parent_ids_without_rows(&rows).len(), 4);
+
+        let tree = build_hierarchy(&rows, &parents, &HierarchyFilter::default());
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].name.as_deref(), Some("Summer"));
+        assert_eq!(tree[0].children[0].children.len(), 2);
+        assert_eq!(tree[0].last_activity, Some(NaiveDate::from_ymd(2020, 1, 3)));
+        assert_eq!(tree[0].account_id.as_deref(), Some("account"));
+
+        // Active ads are dropped, their paused parents are kept
+        let filter = HierarchyFilter {
+            status: Some("paused".to_owned()),
+            ..HierarchyFilter::default()
+        };
+        let tree = build_hierarchy(&rows, &parents, &filter);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].id, "c2");
+        assert!(tree[0].children[0].children.is_empty());
+
+        let filter = HierarchyFilter {
+            name_prefix: Some("ad t".to_owned()),
+            ..HierarchyFilter::default()
+        };
+        let tree = build_hierarchy(&rows, &parents, &filter);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].children[0].children[0].id, "ad2");
+    }
+}
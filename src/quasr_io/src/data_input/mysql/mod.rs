@@ -1,11 +1,22 @@
-use chrono::NaiveDate;
+pub mod catalog;
+pub mod credentials;
+pub mod hierarchy;
+pub mod saved_queries;
+
+use chrono::{NaiveDate, NaiveDateTime};
 use diesel::{
-    dsl::sql_query,
+    mysql::Mysql,
     prelude::*,
-    sql_types::{Date, Double, Nullable, Varchar},
+    query_builder::{AstPass, QueryFragment, QueryId},
+    sql_types::{Date, Datetime, Double, Integer, Nullable, Text, Varchar},
     QueryableByName,
 };
-use quasr_core::{input::InputDataRow, CoreSqlString};
+use quasr_core::{
+    input::{CoreAttribution, InputDataRow, MarketingNodeDetails, PropertyAttributes},
+    CoreSqlString, MarketingNode,
+};
+use serde_json::Value;
+use std::{collections::HashMap, error::Error, fmt};
 #[allow(non_snake_case)]
 #[derive(Debug, QueryableByName)]
 struct DbRow {
@@ -19,6 +30,20 @@ struct DbRow {
     pub marketing_node: Option<String>,
     #[sql_type = "Varchar"]
     pub ad_platform: String,
+    #[sql_type = "Nullable<Varchar>"]
+    pub objective: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub property_status: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub display_status: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub bid_strategy: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub property_type: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub attribution_mode: Option<String>,
+    #[sql_type = "Nullable<Integer>"]
+    pub attribution_window: Option<i32>,
 }
 impl Into<InputDataRow> for DbRow {
     fn into(self) -> InputDataRow {
@@ -28,12 +53,172 @@ impl Into<InputDataRow> for DbRow {
             marketing_node: self.marketing_node,
             metric_name: self.name,
             ad_platform: self.ad_platform,
+            property_attributes: PropertyAttributes {
+                objective: self.objective,
+                property_status: self.property_status,
+                display_status: self.display_status,
+                bid_strategy: self.bid_strategy,
+                property_type: self.property_type,
+            },
+            attribution: match (self.attribution_mode, self.attribution_window) {
+                (Some(mode), Some(window)) => Some(CoreAttribution { mode, window }),
+                _ => None,
+            },
         }
     }
 }
+#[allow(non_snake_case)]
+#[derive(Debug, QueryableByName)]
+struct DbNodeDetailsRow {
+    #[sql_type = "Varchar"]
+    pub marketing_node: String,
+    #[sql_type = "Nullable<Varchar>"]
+    pub name: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub handle: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub propertyStatus: Option<String>,
+    #[sql_type = "Nullable<Datetime>"]
+    pub externalCreatedAt: Option<NaiveDateTime>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub campaignId: Option<String>,
+    #[sql_type = "Nullable<Varchar>"]
+    pub adSetId: Option<String>,
+    #[sql_type = "Nullable<Double>"]
+    pub dailyBudget: Option<f64>,
+}
+#[derive(Debug, QueryableByName)]
+struct DbExplainRow {
+    #[sql_type = "Text"]
+    #[column_name = "EXPLAIN"]
+    pub plan: String,
+}
 
-pub fn load_query_from_db(con: &MysqlConnection, query: CoreSqlString) -> Vec<InputDataRow> {
-    let diesel_sql_query = sql_query(&query.to_string());
-    let db_rows: Vec<DbRow> = diesel_sql_query.load(con).unwrap();
-    db_rows.into_iter().map(|i| i.into()).collect()
+/// A `CoreSqlString` with its parameters bound to its placeholders, behind `prefix`
+struct BoundSql<'a> {
+    prefix: &'static str,
+    query: &'a CoreSqlString,
+}
+impl<'a> BoundSql<'a> {
+    fn new(query: &'a CoreSqlString) -> Self {
+        BoundSql { prefix: "", query }
+    }
+    fn load<U: diesel::deserialize::QueryableByName<Mysql>>(
+        &self,
+        con: &MysqlConnection,
+    ) -> QueryResult<Vec<U>> {
+        con.query_by_name(self)
+    }
+}
+impl QueryFragment<Mysql> for BoundSql<'_> {
+    fn walk_ast(&self, mut out: AstPass<Mysql>) -> QueryResult<()> {
+        out.push_sql(self.prefix);
+        let mut parts = self.query.as_str().split('?');
+        out.push_sql(parts.next().unwrap_or_default());
+        for (param, part) in self.query.params().iter().zip(parts) {
+            out.push_bind_param::<Varchar, _>(param)?;
+            out.push_sql(part);
+        }
+        Ok(())
+    }
+}
+impl QueryId for BoundSql<'_> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+/// Why `load_query_from_db` returned no rows
+#[derive(Debug)]
+pub enum QueryError {
+    /// The query would have returned more than `limit` rows
+    TooManyRows {
+        limit: usize,
+    },
+    /// The database stopped the query once it ran past its `MAX_EXECUTION_TIME`
+    Timeout,
+    Database(diesel::result::Error),
+}
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::TooManyRows { limit } => write!(
+                f,
+                "The query returns more than {} rows, narrow it down with filters or a shorter date range",
+                limit
+            ),
+            QueryError::Timeout => write!(f, "The query took too long to run"),
+            QueryError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for QueryError {}
+impl From<diesel::result::Error> for QueryError {
+    fn from(e: diesel::result::Error) -> Self {
+        match &e {
+            // MySQL's ER_QUERY_TIMEOUT, 3024
+            diesel::result::Error::DatabaseError(_, info)
+                if info
+                    .message()
+                    .contains("maximum statement execution time exceeded") =>
+            {
+                QueryError::Timeout
+            }
+            _ => QueryError::Database(e),
+        }
+    }
+}
+/// Runs `query`, failing instead of returning more than `max_rows` rows
+pub fn load_query_from_db(
+    con: &MysqlConnection,
+    query: CoreSqlString,
+    max_rows: Option<usize>,
+) -> Result<Vec<InputDataRow>, QueryError> {
+    let query = match max_rows {
+        // One more than allowed, to tell a result that hits the limit from one that is cut off
+        Some(limit) => query.with_limit(limit + 1),
+        None => query,
+    };
+    let db_rows: Vec<DbRow> = BoundSql::new(&query).load(con)?;
+    if let Some(limit) = max_rows {
+        if db_rows.len() > limit {
+            return Err(QueryError::TooManyRows { limit });
+        }
+    }
+    Ok(db_rows.into_iter().map(|i| i.into()).collect())
+}
+/// Details of the marketing nodes `query` selects, by node. Nodes without a name get an empty one.
+pub fn load_node_details_from_db(
+    con: &MysqlConnection,
+    query: CoreSqlString,
+) -> Result<HashMap<MarketingNode, MarketingNodeDetails>, QueryError> {
+    let db_rows: Vec<DbNodeDetailsRow> = BoundSql::new(&query).load(con)?;
+    Ok(db_rows
+        .into_iter()
+        .map(|r| {
+            (
+                r.marketing_node,
+                MarketingNodeDetails {
+                    name: r.name.unwrap_or_default(),
+                    handle: r.handle,
+                    property_status: r.propertyStatus,
+                    external_created_at: r.externalCreatedAt,
+                    campaign_id: r.campaignId,
+                    ad_set_id: r.adSetId,
+                    daily_budget: r.dailyBudget,
+                },
+            )
+        })
+        .collect())
+}
+/// Asks the database for its plan for `query`, without running it
+pub fn explain_query_in_db(con: &MysqlConnection, query: &CoreSqlString) -> QueryResult<Value> {
+    let explain = BoundSql {
+        prefix: "EXPLAIN FORMAT=JSON ",
+        query,
+    };
+    let db_rows: Vec<DbExplainRow> = explain.load(con)?;
+    Ok(db_rows
+        .into_iter()
+        .next()
+        .and_then(|r| serde_json::from_str(&r.plan).ok())
+        .unwrap_or(Value::Null))
 }
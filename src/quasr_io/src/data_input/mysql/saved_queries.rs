@@ -0,0 +1,186 @@
+use chrono::NaiveDateTime;
+use diesel::{
+    dsl::sql_query,
+    prelude::*,
+    sql_types::{Char, Datetime, Integer, Text, Varchar},
+    QueryableByName,
+};
+use serde::{ser::Error, Serialize, Serializer};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// A query an organisation saved under a name
+#[derive(Debug, Serialize, QueryableByName)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedQuery {
+    #[sql_type = "Char"]
+    pub id: String,
+    #[sql_type = "Varchar"]
+    pub name: String,
+    /// Latest version of the query
+    #[sql_type = "Integer"]
+    pub version: i32,
+    #[sql_type = "Datetime"]
+    #[column_name = "createdAt"]
+    pub created_at: NaiveDateTime,
+    #[sql_type = "Datetime"]
+    #[column_name = "updatedAt"]
+    pub updated_at: NaiveDateTime,
+}
+#[derive(Debug, Serialize, QueryableByName)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedQueryVersion {
+    #[sql_type = "Integer"]
+    pub version: i32,
+    /// The `AdsFlowQuery`, as JSON
+    #[sql_type = "Text"]
+    #[serde(serialize_with = "as_json")]
+    pub query: String,
+    #[sql_type = "Datetime"]
+    #[column_name = "createdAt"]
+    pub created_at: NaiveDateTime,
+}
+fn as_json<S: Serializer>(query: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serde_json::from_str::<Value>(query)
+        .map_err(S::Error::custom)?
+        .serialize(serializer)
+}
+
+const SELECT_SAVED_QUERIES: &str = "SELECT SavedQueries.id,name,MAX(version) AS version,\
+     SavedQueries.createdAt,updatedAt \
+     FROM SavedQueries JOIN SavedQueryVersions ON SavedQueries.id=SavedQueryVersions.savedQueryId \
+     WHERE organizationId = ? ";
+
+pub fn list_saved_queries(con: &MysqlConnection, org_id: &str) -> QueryResult<Vec<SavedQuery>> {
+    sql_query(format!(
+        "{}GROUP BY SavedQueries.id ORDER BY name",
+        SELECT_SAVED_QUERIES
+    ))
+    .bind::<Char, _>(org_id)
+    .load(con)
+}
+pub fn load_saved_query(
+    con: &MysqlConnection,
+    org_id: &str,
+    id: &str,
+) -> QueryResult<Option<SavedQuery>> {
+    sql_query(format!(
+        "{}AND SavedQueries.id = ? GROUP BY SavedQueries.id",
+        SELECT_SAVED_QUERIES
+    ))
+    .bind::<Char, _>(org_id)
+    .bind::<Char, _>(id)
+    .get_result(con)
+    .optional()
+}
+fn insert_version(
+    con: &MysqlConnection,
+    id: &str,
+    version: i32,
+    query: &str,
+) -> QueryResult<usize> {
+    sql_query("INSERT INTO SavedQueryVersions (savedQueryId, version, query) VALUES (?, ?, ?)")
+        .bind::<Char, _>(id)
+        .bind::<Integer, _>(version)
+        .bind::<Text, _>(query)
+        .execute(con)
+}
+/// Saves `query` as the first version of a new saved query. Fails if the organisation already
+/// has a query with that name.
+pub fn create_saved_query(
+    con: &MysqlConnection,
+    org_id: &str,
+    name: &str,
+    query: &str,
+) -> QueryResult<SavedQuery> {
+    con.transaction(|| {
+        let id = Uuid::new_v4().to_string();
+        sql_query("INSERT INTO SavedQueries (id, organizationId, name) VALUES (?, ?, ?)")
+            .bind::<Char, _>(&id)
+            .bind::<Char, _>(org_id)
+            .bind::<Varchar, _>(name)
+            .execute(con)?;
+        insert_version(con, &id, 1, query)?;
+        load_saved_query(con, org_id, &id)?.ok_or(diesel::result::Error::NotFound)
+    })
+}
+#[derive(QueryableByName)]
+struct DbId {
+    #[sql_type = "Char"]
+    #[allow(dead_code)]
+    id: String,
+}
+/// Saves `query` as the next version of the saved query, `None` if there is no such query.
+/// The saved query's row stays locked until the version is inserted, so concurrent saves are
+/// numbered one after the other.
+pub fn add_saved_query_version(
+    con: &MysqlConnection,
+    org_id: &str,
+    id: &str,
+    query: &str,
+) -> QueryResult<Option<SavedQuery>> {
+    con.transaction(|| {
+        let locked: Option<DbId> =
+            sql_query("SELECT id FROM SavedQueries WHERE organizationId = ? AND id = ? FOR UPDATE")
+                .bind::<Char, _>(org_id)
+                .bind::<Char, _>(id)
+                .get_result(con)
+                .optional()?;
+        if locked.is_none() {
+            return Ok(None);
+        }
+        let saved = match load_saved_query(con, org_id, id)? {
+            Some(saved) => saved,
+            None => return Ok(None),
+        };
+        insert_version(con, id, saved.version + 1, query)?;
+        sql_query("UPDATE SavedQueries SET updatedAt = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind::<Char, _>(id)
+            .execute(con)?;
+        load_saved_query(con, org_id, id)
+    })
+}
+/// The given version of the saved query, or its latest one
+pub fn load_saved_query_version(
+    con: &MysqlConnection,
+    org_id: &str,
+    id: &str,
+    version: Option<i32>,
+) -> QueryResult<Option<SavedQueryVersion>> {
+    let query = sql_query(format!(
+        "SELECT version,query,SavedQueryVersions.createdAt \
+         FROM SavedQueryVersions JOIN SavedQueries ON SavedQueries.id=SavedQueryVersions.savedQueryId \
+         WHERE organizationId = ? AND SavedQueries.id = ? {}\
+         ORDER BY version DESC LIMIT 1",
+        if version.is_some() { "AND version = ? " } else { "" }
+    ))
+    .bind::<Char, _>(org_id)
+    .bind::<Char, _>(id);
+    match version {
+        Some(version) => query.bind::<Integer, _>(version).get_result(con),
+        None => query.get_result(con),
+    }
+    .optional()
+}
+pub fn list_saved_query_versions(
+    con: &MysqlConnection,
+    org_id: &str,
+    id: &str,
+) -> QueryResult<Vec<SavedQueryVersion>> {
+    sql_query(
+        "SELECT version,query,SavedQueryVersions.createdAt \
+         FROM SavedQueryVersions JOIN SavedQueries ON SavedQueries.id=SavedQueryVersions.savedQueryId \
+         WHERE organizationId = ? AND SavedQueries.id = ? ORDER BY version",
+    )
+    .bind::<Char, _>(org_id)
+    .bind::<Char, _>(id)
+    .load(con)
+}
+/// Deletes the saved query with all its versions. Returns whether there was such a query
+pub fn delete_saved_query(con: &MysqlConnection, org_id: &str, id: &str) -> QueryResult<bool> {
+    sql_query("DELETE FROM SavedQueries WHERE organizationId = ? AND id = ?")
+        .bind::<Char, _>(org_id)
+        .bind::<Char, _>(id)
+        .execute(con)
+        .map(|deleted| deleted > 0)
+}
@@ -1,3 +1,14 @@
+table! {
+    #[allow(non_snake_case)]
+    ApiKeys (keyHash, organizationId) {
+        keyHash -> Char,
+        organizationId -> Char,
+        description -> Nullable<Varchar>,
+        createdAt -> Datetime,
+        revokedAt -> Nullable<Datetime>,
+    }
+}
+
 table! {
     #[allow(non_snake_case)]
     Properties (id) {
@@ -25,6 +36,27 @@ table! {
     }
 }
 
+table! {
+    #[allow(non_snake_case)]
+    SavedQueries (id) {
+        id -> Char,
+        organizationId -> Char,
+        name -> Varchar,
+        createdAt -> Datetime,
+        updatedAt -> Datetime,
+    }
+}
+
+table! {
+    #[allow(non_snake_case)]
+    SavedQueryVersions (savedQueryId, version) {
+        savedQueryId -> Char,
+        version -> Integer,
+        query -> Text,
+        createdAt -> Datetime,
+    }
+}
+
 table! {
     #[allow(non_snake_case)]
     UpperFunnelMetricFields (id) {
@@ -62,7 +94,15 @@ table! {
     }
 }
 
+joinable!(SavedQueryVersions -> SavedQueries (savedQueryId));
 joinable!(UpperFunnelMetricValues -> Properties (propertyId));
 joinable!(UpperFunnelMetricValues -> UpperFunnelMetricFields (upperFunnelMetricFieldId));
 
-allow_tables_to_appear_in_same_query!(Properties, UpperFunnelMetricFields, UpperFunnelMetricValues,);
+allow_tables_to_appear_in_same_query!(
+    ApiKeys,
+    Properties,
+    SavedQueries,
+    SavedQueryVersions,
+    UpperFunnelMetricFields,
+    UpperFunnelMetricValues,
+);
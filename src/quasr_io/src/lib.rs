@@ -1,3 +1,7 @@
+pub mod clock;
 pub mod data_input;
 mod date_format;
+pub mod output_arrow;
 pub mod output_csv;
+pub mod output_parquet;
+pub mod output_xlsx;
@@ -0,0 +1,193 @@
+use arrow::{
+    array::{ArrayRef, Date32Array, Float64Array, Int64Array, StringArray},
+    datatypes::{DataType, Field, Schema},
+    error::Result,
+    ipc::writer::StreamWriter,
+    record_batch::RecordBatch,
+};
+use chrono::{Datelike, NaiveDate};
+use quasr_core::{input::QuasrQuery, OutputDataRow};
+use std::{collections::HashMap, iter::FromIterator, sync::Arc};
+
+/// Key of the metadata entry with the query, as JSON
+pub const QUERY_METADATA_KEY: &str = "quasr.query";
+/// `num_days_from_ce` of 1970-01-01
+const UNIX_EPOCH_DAYS_FROM_CE: i32 = 719_163;
+
+/// Same columns as the CSV, with dates as dates and nulls instead of empty strings. Instead of
+/// the `metadata` JSON there is a column for each property attribute of the breakdown, and one
+/// with the name of the marketing node when the query includes its details.
+fn schema(query: &QuasrQuery) -> Schema {
+    let mut fields = vec![
+        Field::new("startDate", DataType::Date32, false),
+        Field::new("endDate", DataType::Date32, false),
+        Field::new("metricIndex", DataType::Int64, false),
+        Field::new("value", DataType::Float64, true),
+        Field::new("marketingNode", DataType::Utf8, true),
+        Field::new("geography", DataType::Utf8, true),
+        Field::new("adPlatform", DataType::Utf8, true),
+    ];
+    fields.extend(
+        query
+            .property_attribute_breakdown
+            .iter()
+            .map(|a| Field::new(a.to_database_column_string(), DataType::Utf8, true)),
+    );
+    if includes_node_names(query) {
+        fields.push(Field::new("marketingNodeName", DataType::Utf8, true));
+    }
+    Schema::new(fields)
+}
+fn includes_node_names(query: &QuasrQuery) -> bool {
+    query.include_metadata && query.marketing_node_breakdown.is_some()
+}
+/// Days since the epoch
+fn to_date32(date: NaiveDate) -> i32 {
+    date.num_days_from_ce() - UNIX_EPOCH_DAYS_FROM_CE
+}
+/// `query` as schema metadata, under `QUERY_METADATA_KEY`
+fn query_metadata(query: &QuasrQuery) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        QUERY_METADATA_KEY.to_owned(),
+        serde_json::to_string(query).unwrap_or_default(),
+    );
+    metadata
+}
+/// The rows of `query` as a single record batch, with `metadata` on its schema
+pub fn record_batch(
+    rows: &[OutputDataRow],
+    query: &QuasrQuery,
+    metadata: HashMap<String, String>,
+) -> Result<RecordBatch> {
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(Date32Array::from_iter_values(
+            rows.iter().map(|r| to_date32(r.start_date)),
+        )),
+        Arc::new(Date32Array::from_iter_values(
+            rows.iter().map(|r| to_date32(r.end_date)),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            rows.iter().map(|r| r.metric_index as i64),
+        )),
+        // Values that aren't finite numbers are null, ratios divided by zero are 0 like in the CSV
+        Arc::new(Float64Array::from_iter(
+            rows.iter().map(|r| Some(r.value).filter(|v| v.is_finite())),
+        )),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.marketing_node.as_deref()),
+        )),
+        // There is no geography breakdown yet
+        Arc::new(StringArray::new_null(rows.len())),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.ad_platform.as_deref()),
+        )),
+    ];
+    for attribute in &query.property_attribute_breakdown {
+        columns.push(Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.property_attributes.get(*attribute)),
+        )));
+    }
+    if includes_node_names(query) {
+        columns.push(Arc::new(StringArray::from_iter(
+            rows.iter()
+                .map(|r| r.node_details.as_ref().map(|d| d.name.as_str())),
+        )));
+    }
+    RecordBatch::try_new(Arc::new(schema(query).with_metadata(metadata)), columns)
+}
+/// Writes the rows in the Arrow IPC streaming format, with `query` in the schema metadata
+pub fn qs_rows_to_arrow_stream(rows: &[OutputDataRow], query: &QuasrQuery) -> Result<Vec<u8>> {
+    let batch = record_batch(rows, query, query_metadata(query))?;
+    let mut writer = StreamWriter::try_new(vec![], &batch.schema())?;
+    writer.write(&batch)?;
+    writer.into_inner()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{qs_rows_to_arrow_stream, QUERY_METADATA_KEY};
+    use crate::data_input::json::AdsFlowQuery;
+    use arrow::{
+        array::{Array, Date32Array, Float64Array, StringArray},
+        ipc::reader::StreamReader,
+    };
+    use chrono::NaiveDate;
+    use quasr_core::{
+        input::{CorePropertyAttribute, MarketingNodeDetails, PropertyAttributes, QuasrQuery},
+        OutputDataRow,
+    };
+    use std::{convert::TryInto, io::Cursor};
+
+    #[test]
+    fn test_qs_rows_to_arrow_stream() {
+        let query: AdsFlowQuery =
+            serde_json::from_str(include_str!("data_input/json/data/query.json")).unwrap();
+        let mut query: QuasrQuery = query.try_into().unwrap();
+        query.property_attribute_breakdown = vec![
+            CorePropertyAttribute::Objective,
+            CorePropertyAttribute::BidStrategy,
+        ];
+        query.include_metadata = true;
+        let date = NaiveDate::from_ymd(2020, 6, 1);
+        let property_attributes = PropertyAttributes {
+            objective: Some("CONVERSIONS".to_owned()),
+            ..PropertyAttributes::default()
+        };
+        let rows = vec![OutputDataRow {
+            value: 4.0,
+            start_date: date,
+            end_date: date,
+            metric_index: 0,
+            marketing_node: Some("mnode1".to_owned()),
+            ad_platform: Some("facebook".to_owned()),
+            property_attributes,
+            node_details: Some(MarketingNodeDetails {
+                name: "Summer sale".to_owned(),
+                handle: None,
+                property_status: None,
+                external_created_at: None,
+                campaign_id: None,
+                ad_set_id: None,
+                daily_budget: None,
+            }),
+        }];
+        let bytes = qs_rows_to_arrow_stream(&rows, &query).unwrap();
+        let mut reader = StreamReader::try_new(Cursor::new(bytes), None).unwrap();
+        assert!(reader.schema().metadata()[QUERY_METADATA_KEY].contains("\"orgId\""));
+        let batch = reader.next().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 1);
+        let dates = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Date32Array>()
+            .unwrap();
+        assert_eq!(dates.value_as_date(0), Some(date));
+        let values = batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(values.value(0), 4.0);
+        assert!(batch.column(5).is_null(0));
+        let names: Vec<&str> = batch
+            .schema_ref()
+            .fields()
+            .iter()
+            .skip(7)
+            .map(|f| f.name().as_str())
+            .collect();
+        assert_eq!(names, ["objective", "bidStrategy", "marketingNodeName"]);
+        let string = |i: usize| {
+            batch
+                .column(i)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+        };
+        assert_eq!(string(7).value(0), "CONVERSIONS");
+        assert!(string(8).is_null(0));
+        assert_eq!(string(9).value(0), "Summer sale");
+        assert!(reader.next().is_none());
+    }
+}
@@ -1,7 +1,13 @@
 use super::date_format;
 use chrono::NaiveDate;
-use quasr_core::OutputDataRow;
+use quasr_core::{
+    input::{CorePropertyAttribute, MarketingNodeDetails},
+    OutputDataRow,
+};
 use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
 
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -31,8 +37,45 @@ impl QueryServerRow {
         ]
     }
 }
+/// Property attributes the row was broken down by, keyed by column name, and the details of
+/// its marketing node under `node` when they were requested
+pub(crate) fn row_metadata(row: &OutputDataRow) -> String {
+    let mut metadata = CorePropertyAttribute::all()
+        .iter()
+        .filter_map(|a| {
+            row.property_attributes.get(*a).map(|v| {
+                (
+                    a.to_database_column_string().to_owned(),
+                    Value::from(v.clone()),
+                )
+            })
+        })
+        .collect::<Map<String, Value>>();
+    if let Some(details) = &row.node_details {
+        metadata.insert("node".to_owned(), node_details_to_json(details));
+    }
+    if metadata.is_empty() {
+        "".to_owned()
+    } else {
+        Value::Object(metadata).to_string()
+    }
+}
+fn node_details_to_json(details: &MarketingNodeDetails) -> Value {
+    json!({
+        "name": details.name,
+        "handle": details.handle,
+        "propertyStatus": details.property_status,
+        "externalCreatedAt": details
+            .external_created_at
+            .map(|d| d.format(DATETIME_FORMAT).to_string()),
+        "campaignId": details.campaign_id,
+        "adSetId": details.ad_set_id,
+        "dailyBudget": details.daily_budget,
+    })
+}
 impl From<OutputDataRow> for QueryServerRow {
     fn from(row: OutputDataRow) -> Self {
+        let metadata = row_metadata(&row);
         QueryServerRow {
             start_date: row.start_date,
             end_date: row.end_date,
@@ -41,7 +84,7 @@ impl From<OutputDataRow> for QueryServerRow {
             marketing_node: row.marketing_node,
             geography: "".to_owned(),
             ad_platform: row.ad_platform,
-            metadata: "".to_owned(),
+            metadata,
         }
     }
 }
@@ -0,0 +1,94 @@
+use super::output_arrow::{record_batch, QUERY_METADATA_KEY};
+use parquet::{
+    arrow::ArrowWriter, basic::Compression, errors::Result, file::properties::WriterProperties,
+    format::KeyValue,
+};
+use quasr_core::{input::QuasrQuery, OutputDataRow};
+use std::collections::HashMap;
+
+/// Writes the rows as a Parquet file, with `query` in its metadata under `QUERY_METADATA_KEY`
+pub fn qs_rows_to_parquet(rows: &[OutputDataRow], query: &QuasrQuery) -> Result<Vec<u8>> {
+    let batch = record_batch(rows, query, HashMap::new())?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_key_value_metadata(Some(vec![KeyValue::new(
+            QUERY_METADATA_KEY.to_owned(),
+            serde_json::to_string(query).unwrap_or_default(),
+        )]))
+        .build();
+    let mut writer = ArrowWriter::try_new(vec![], batch.schema(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.into_inner()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{qs_rows_to_parquet, QUERY_METADATA_KEY};
+    use crate::data_input::json::AdsFlowQuery;
+    use arrow::array::{Array, Date32Array, Float64Array, Int64Array, StringArray};
+    use bytes::Bytes;
+    use chrono::NaiveDate;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use quasr_core::{
+        input::{PropertyAttributes, QuasrQuery},
+        OutputDataRow,
+    };
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_qs_rows_to_parquet() {
+        let query: AdsFlowQuery =
+            serde_json::from_str(include_str!("data_input/json/data/query.json")).unwrap();
+        let query: QuasrQuery = query.try_into().unwrap();
+        let date = NaiveDate::from_ymd(2020, 6, 1);
+        let row = |value: f64, node: Option<&str>| OutputDataRow {
+            value,
+            start_date: date,
+            end_date: date,
+            metric_index: 1,
+            marketing_node: node.map(|n| n.to_owned()),
+            ad_platform: None,
+            property_attributes: PropertyAttributes::default(),
+            node_details: None,
+        };
+        let bytes =
+            qs_rows_to_parquet(&[row(2.5, Some("mnode1")), row(f64::NAN, None)], &query).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(bytes)).unwrap();
+        let metadata = reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap();
+        assert_eq!(metadata[0].key, QUERY_METADATA_KEY);
+        assert!(metadata[0].value.as_ref().unwrap().contains("\"orgId\""));
+        let batch = reader.build().unwrap().next().unwrap().unwrap();
+        let dates = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Date32Array>()
+            .unwrap();
+        assert_eq!(dates.value_as_date(0), Some(date));
+        let indices = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(indices.value(1), 1);
+        let values = batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(values.value(0), 2.5);
+        assert!(values.is_null(1));
+        let nodes = batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(nodes.value(0), "mnode1");
+        assert!(nodes.is_null(1));
+        // Neither attributes nor node details were asked for
+        assert_eq!(batch.num_columns(), 7);
+    }
+}
@@ -0,0 +1,264 @@
+use chrono::{Datelike, NaiveDate};
+use quasr_core::{
+    input::{PropertyAttributes, QuasrQuery},
+    MetricName, OutputDataRow,
+};
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, Worksheet, XlsxError};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Dates, node, platform and attributes of a row of the pivoted sheet
+type RowKey = (
+    NaiveDate,
+    NaiveDate,
+    Option<String>,
+    Option<String>,
+    PropertyAttributes,
+);
+
+/// Number formats of the metric columns
+enum MetricFormat {
+    Number,
+    Currency,
+    Percent,
+}
+/// Division metrics are percentages. Other metrics are currency amounts when all the metrics
+/// they are built from are.
+fn metric_format(query: &QuasrQuery, index: usize, currency: &HashSet<MetricName>) -> MetricFormat {
+    let metric = &query.metrics[index];
+    if metric.is_division() {
+        MetricFormat::Percent
+    } else if metric
+        .base_metric_names()
+        .iter()
+        .all(|name| currency.contains(name))
+    {
+        MetricFormat::Currency
+    } else {
+        MetricFormat::Number
+    }
+}
+fn excel_date(date: NaiveDate) -> Result<ExcelDateTime, XlsxError> {
+    ExcelDateTime::from_ymd(date.year() as u16, date.month() as u8, date.day() as u8)
+}
+/// One row per date range, node, platform and attributes, with a column per metric
+fn results_sheet(
+    rows: &[OutputDataRow],
+    query: &QuasrQuery,
+    currency: &HashSet<MetricName>,
+) -> Result<Worksheet, XlsxError> {
+    let mut pivoted: Vec<(RowKey, Vec<Option<f64>>)> = vec![];
+    let mut positions: HashMap<RowKey, usize> = HashMap::new();
+    let mut names: HashMap<String, String> = HashMap::new();
+    for r in rows {
+        // Summation and division rows have a platform even without a platform breakdown
+        let ad_platform = if query.ad_platform_breakdown {
+            r.ad_platform.clone()
+        } else {
+            None
+        };
+        let key = (
+            r.start_date,
+            r.end_date,
+            r.marketing_node.clone(),
+            ad_platform,
+            r.property_attributes.clone(),
+        );
+        let position = *positions.entry(key.clone()).or_insert_with(|| {
+            pivoted.push((key, vec![None; query.metrics.len()]));
+            pivoted.len() - 1
+        });
+        pivoted[position].1[r.metric_index] = Some(r.value).filter(|v| v.is_finite());
+        if let (Some(node), Some(details)) = (&r.marketing_node, &r.node_details) {
+            names.insert(node.clone(), details.name.clone());
+        }
+    }
+    pivoted.sort_by(|(a, _), (b, _)| (a.0, &a.2, &a.3).cmp(&(b.0, &b.2, &b.3)));
+
+    let header = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd");
+    // Built-in format 7 is shown in the currency of the reader's locale
+    let currency_format = Format::new().set_num_format_index(7);
+    let percent = Format::new().set_num_format("0.00%");
+    let number = Format::new().set_num_format("#,##0.##");
+
+    let mut sheet = Worksheet::new();
+    sheet.set_name("Results")?;
+    let mut columns = vec!["Start date", "End date"];
+    if query.marketing_node_breakdown.is_some() {
+        columns.push("Marketing node");
+        if !names.is_empty() {
+            columns.push("Name");
+        }
+    }
+    if query.ad_platform_breakdown {
+        columns.push("Ad platform");
+    }
+    let attributes = &query.property_attribute_breakdown;
+    columns.extend(attributes.iter().map(|a| a.to_database_column_string()));
+    for (col, name) in columns.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *name, &header)?;
+    }
+    let first_metric = columns.len() as u16;
+    let formats: Vec<&Format> = (0..query.metrics.len())
+        .map(|i| match metric_format(query, i, currency) {
+            MetricFormat::Number => &number,
+            MetricFormat::Currency => &currency_format,
+            MetricFormat::Percent => &percent,
+        })
+        .collect();
+    for (i, metric) in query.metrics.iter().enumerate() {
+        sheet.write_string_with_format(0, first_metric + i as u16, metric.label(), &header)?;
+    }
+
+    for (i, ((start_date, end_date, node, platform, property_attributes), values)) in
+        pivoted.iter().enumerate()
+    {
+        let row = i as u32 + 1;
+        sheet.write_datetime_with_format(row, 0, &excel_date(*start_date)?, &date)?;
+        sheet.write_datetime_with_format(row, 1, &excel_date(*end_date)?, &date)?;
+        let mut col = 2;
+        if query.marketing_node_breakdown.is_some() {
+            if let Some(node) = node {
+                sheet.write_string(row, col, node)?;
+                if let Some(name) = names.get(node) {
+                    sheet.write_string(row, col + 1, name)?;
+                }
+            }
+            col += if names.is_empty() { 1 } else { 2 };
+        }
+        if query.ad_platform_breakdown {
+            if let Some(platform) = platform {
+                sheet.write_string(row, col, platform)?;
+            }
+            col += 1;
+        }
+        for attribute in attributes {
+            if let Some(value) = property_attributes.get(*attribute) {
+                sheet.write_string(row, col, value)?;
+            }
+            col += 1;
+        }
+        for (i, value) in values.iter().enumerate() {
+            if let Some(value) = value {
+                sheet.write_number_with_format(row, col + i as u16, *value, formats[i])?;
+            }
+        }
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    sheet.autofit();
+    Ok(sheet)
+}
+/// The query's parameters, one per row, with the metrics listed by their column
+fn query_sheet(query: &QuasrQuery) -> Result<Worksheet, XlsxError> {
+    let header = Format::new().set_bold();
+    let mut sheet = Worksheet::new();
+    sheet.set_name("Query")?;
+    sheet.write_string_with_format(0, 0, "Parameter", &header)?;
+    sheet.write_string_with_format(0, 1, "Value", &header)?;
+    let mut row = 1;
+    for (i, metric) in query.metrics.iter().enumerate() {
+        sheet.write_string(row, 0, format!("Metric {}", i + 1))?;
+        sheet.write_string(row, 1, metric.label())?;
+        row += 1;
+    }
+    if let Value::Object(parameters) = serde_json::to_value(query).unwrap_or(Value::Null) {
+        for (name, value) in parameters {
+            if name == "metrics" {
+                continue;
+            }
+            let value = match value {
+                Value::String(s) => s,
+                Value::Null => "".to_owned(),
+                other => other.to_string(),
+            };
+            sheet.write_string(row, 0, name)?;
+            sheet.write_string(row, 1, value)?;
+            row += 1;
+        }
+    }
+    sheet.autofit();
+    Ok(sheet)
+}
+/// Writes the rows as an Excel workbook, pivoted to a column per metric, with a second sheet
+/// recording the query. `currency` are the names of the organisation's metrics that are amounts
+/// of money.
+pub fn qs_rows_to_xlsx(
+    rows: &[OutputDataRow],
+    query: &QuasrQuery,
+    currency: &HashSet<MetricName>,
+) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    workbook.push_worksheet(results_sheet(rows, query, currency)?);
+    workbook.push_worksheet(query_sheet(query)?);
+    workbook.save_to_buffer()
+}
+
+#[cfg(test)]
+mod test {
+    use super::qs_rows_to_xlsx;
+    use crate::data_input::json::AdsFlowQuery;
+    use chrono::NaiveDate;
+    use quasr_core::{
+        input::{PropertyAttributes, QuasrQuery},
+        set, CoreMetric, OutputDataRow,
+    };
+    use std::{
+        collections::HashSet,
+        convert::TryInto,
+        io::{Cursor, Read},
+    };
+    use zip::ZipArchive;
+
+    #[test]
+    fn test_qs_rows_to_xlsx() {
+        let query: AdsFlowQuery =
+            serde_json::from_str(include_str!("data_input/json/data/query.json")).unwrap();
+        let mut query: QuasrQuery = query.try_into().unwrap();
+        query.metrics = vec![
+            CoreMetric::UpperFunnelMetric("Cost".to_owned()),
+            CoreMetric::DivisionMetric {
+                numerator: set!["Clicks"],
+                denominator: set!["Impressions"],
+            },
+        ];
+        let date = NaiveDate::from_ymd(2018, 9, 18);
+        query.ad_platform_breakdown = false;
+        let row = |metric_index: usize, value: f64, ad_platform: Option<&str>| OutputDataRow {
+            value,
+            start_date: date,
+            end_date: date,
+            metric_index,
+            marketing_node: Some("mnode1".to_owned()),
+            ad_platform: ad_platform.map(|p| p.to_owned()),
+            property_attributes: PropertyAttributes::default(),
+            node_details: None,
+        };
+        // Like the rows of a query mixing upper funnel and division metrics
+        let rows = [row(0, 12.5, None), row(1, 0.02, Some("Twitter"))];
+        let bytes = qs_rows_to_xlsx(&rows, &query, &set!["Cost"]).unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut read = |name: &str| {
+            let mut xml = String::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut xml)
+                .unwrap();
+            xml
+        };
+        let strings = read("xl/sharedStrings.xml");
+        assert!(strings.contains("<t>Clicks / Impressions</t>"));
+        assert!(strings.contains("<t>mnode1</t>"));
+        assert!(strings.contains("<t>orgId</t>"));
+        let styles = read("xl/styles.xml");
+        assert!(styles.contains(r#"formatCode="0.00%""#));
+        assert!(styles.contains(r#"numFmtId="7""#));
+        // Dates are serial numbers, 2018-09-18 is day 43361
+        let results = read("xl/worksheets/sheet1.xml");
+        assert!(results.contains("<v>43361</v>"));
+        assert!(results.contains("<v>12.5</v>"));
+        // Both metrics are on the same row, after the header
+        assert_eq!(results.matches("<row ").count(), 2);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{auth::ApiKey, error::ApiError, settings::Settings};
+use rocket::{
+    catch,
+    http::{RawStr, Status},
+    request::{self, FromRequest},
+    Outcome, Request, State,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often buckets that have filled up again are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits on how hard an API key can query an organisation. `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    /// Requests refilled into the bucket every second
+    pub requests_per_second: Option<f64>,
+    /// Requests that can be made at once after a quiet period, `requests_per_second` if unset
+    pub burst: Option<f64>,
+    /// Queries that can be running at the same time
+    pub max_concurrent: Option<usize>,
+}
+/// Hash of the API key and the organisation, if the limits are per organisation
+type Client = (String, Option<String>);
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again if no more requests are made, counting the one being made,
+    /// and can be forgotten
+    full_at: Instant,
+}
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<Client, Bucket>,
+    swept: Option<Instant>,
+}
+/// Token buckets and in-flight query counts, kept in memory per API key and organisation
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    in_flight: Arc<Mutex<HashMap<Client, usize>>>,
+}
+/// Counts as an in-flight query until it is dropped
+pub struct QueryPermit {
+    in_flight: Arc<Mutex<HashMap<Client, usize>>>,
+    client: Client,
+}
+impl Drop for QueryPermit {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.client);
+            }
+        }
+    }
+}
+fn too_many_requests(message: &str, retry_after: u64) -> ApiError {
+    let mut e = ApiError::new(Status::TooManyRequests, message);
+    e.retry_after = Some(retry_after.max(1));
+    e
+}
+impl RateLimiter {
+    /// Takes a request from the client's bucket, failing when it is empty
+    fn take_token(&self, client: &Client, limit: &RateLimit) -> Result<(), ApiError> {
+        let rate = match limit.requests_per_second {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        let burst = limit.burst.unwrap_or(rate).max(1.0);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // A forgotten bucket is full, just like a new one
+        if buckets
+            .swept
+            .map_or(true, |swept| now.duration_since(swept) >= SWEEP_INTERVAL)
+        {
+            buckets.by_client.retain(|_, bucket| bucket.full_at > now);
+            buckets.swept = Some(now);
+        }
+        let bucket = buckets.by_client.entry(client.clone()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        bucket.full_at = now + Duration::from_secs_f64((burst - bucket.tokens + 1.0) / rate);
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / rate;
+            return Err(too_many_requests(
+                "Too many requests, slow down",
+                wait.ceil() as u64,
+            ));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+    /// Takes a slot for a query and a request from the client's bucket, failing with a 429 when
+    /// either is used up. A request turned away for the slots doesn't use up the bucket.
+    pub fn acquire(&self, client: Client, limit: &RateLimit) -> Result<QueryPermit, ApiError> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.get(&client).copied().unwrap_or(0);
+        if limit.max_concurrent.map_or(false, |max| count >= max) {
+            return Err(too_many_requests(
+                "Too many queries running at the same time",
+                1,
+            ));
+        }
+        self.take_token(&client, limit)?;
+        in_flight.insert(client.clone(), count + 1);
+        Ok(QueryPermit {
+            in_flight: self.in_flight.clone(),
+            client,
+        })
+    }
+}
+/// Why the request guard turned the request away, for the 429 catcher to respond with
+struct Rejection(Option<ApiError>);
+/// Request guard for the routes that run queries, declared after `ApiKey` and before the
+/// guards that take a database connection. Routes with an `<org_id>` segment are limited per API
+/// key and that organisation. The others are limited per API key with the `[global]` limits, and
+/// once they know the organisation from the body, per organisation with `Throttle::acquire`.
+/// The guard holds a query slot until it is dropped.
+pub struct Throttle<'r> {
+    limiter: State<'r, RateLimiter>,
+    settings: State<'r, Settings>,
+    key_hash: String,
+    _permit: QueryPermit,
+}
+impl<'r> Throttle<'r> {
+    /// Takes a request and a query slot of the organisation's limits, for the routes that don't
+    /// have it in their path. The permit has to be held while the query runs.
+    pub fn acquire(&self, org_id: &str) -> Result<QueryPermit, ApiError> {
+        self.limiter.acquire(
+            (self.key_hash.clone(), Some(org_id.to_owned())),
+            &self.settings.org(org_id).rate_limit,
+        )
+    }
+}
+/// The organisation in the request's path, when its route has an `<org_id>` segment
+fn path_org_id(request: &Request) -> Option<String> {
+    let position = request
+        .route()?
+        .uri
+        .segments()
+        .position(|segment| segment == "<org_id>")?;
+    let segment = request.uri().segments().nth(position)?;
+    RawStr::from_str(segment)
+        .percent_decode()
+        .ok()
+        .map(|org_id| org_id.into_owned())
+}
+impl<'a, 'r> FromRequest<'a, 'r> for Throttle<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        // Only valid keys get buckets, the key was already checked by the `ApiKey` guard
+        let key = match request.guard::<ApiKey>() {
+            Outcome::Success(key) => key,
+            Outcome::Failure((status, _)) => return Outcome::Failure((status, ())),
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
+        let limiter = request.guard::<State<RateLimiter>>()?;
+        let settings = request.guard::<State<Settings>>()?;
+        let org_id = path_org_id(request);
+        if let Some(org_id) = &org_id {
+            if key.authorize(org_id).is_err() {
+                return Outcome::Failure((Status::Forbidden, ()));
+            }
+        }
+        let limit = match &org_id {
+            Some(org_id) => &settings.org(org_id).rate_limit,
+            None => settings.default_rate_limit(),
+        };
+        match limiter.acquire((key.key_hash.clone(), org_id), limit) {
+            Ok(permit) => Outcome::Success(Throttle {
+                limiter,
+                settings,
+                key_hash: key.key_hash,
+                _permit: permit,
+            }),
+            Err(e) => {
+                request.local_cache(|| Rejection(Some(e)));
+                Outcome::Failure((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+/// Responds to requests the guard turned away with why, and when to retry
+#[catch(429)]
+pub fn rejected(request: &Request) -> ApiError {
+    request
+        .local_cache(|| Rejection(None))
+        .0
+        .clone()
+        .unwrap_or_else(|| Status::TooManyRequests.into())
+}
@@ -0,0 +1,217 @@
+use crate::{
+    answer_query,
+    auth::ApiKey,
+    check_metric_names,
+    error::ApiError,
+    known_metric_names, known_metrics,
+    logging::{query_span, RequestId},
+    parse_date,
+    rate_limit::Throttle,
+    settings::Settings,
+    sql_for,
+    telemetry::Telemetry,
+    to_quasr_query, DbConn, QSResponse,
+};
+use diesel::result::{DatabaseErrorKind, Error};
+use quasr_io::data_input::{
+    json::AdsFlowQuery,
+    mysql::saved_queries::{
+        add_saved_query_version, create_saved_query, delete_saved_query, list_saved_queries,
+        list_saved_query_versions, load_saved_query, load_saved_query_version, SavedQuery,
+        SavedQueryVersion,
+    },
+};
+use rocket::{
+    delete, get,
+    http::Status,
+    post, put,
+    response::status::{Created, NoContent},
+    State,
+};
+use rocket_contrib::json::Json;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Deserialize)]
+pub struct NewSavedQuery {
+    name: String,
+    query: AdsFlowQuery,
+}
+fn db_error(e: Error) -> ApiError {
+    match e {
+        // The versions' primary key, another version was saved at the same time
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+            if info.message().contains("PRIMARY") =>
+        {
+            ApiError::new(
+                Status::Conflict,
+                "The saved query was changed at the same time, try again",
+            )
+        }
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => ApiError::new(
+            Status::Conflict,
+            "There is already a saved query with that name",
+        ),
+        e => ApiError::internal(&e.to_string()),
+    }
+}
+fn not_found() -> ApiError {
+    ApiError::new(Status::NotFound, "No such saved query")
+}
+/// Checks that the query belongs to the organisation and can be answered, and returns it as JSON
+fn validate(
+    conn: &DbConn,
+    settings: &Settings,
+    org_id: &str,
+    query: AdsFlowQuery,
+) -> Result<String, ApiError> {
+    if query.org_id() != org_id {
+        return Err(ApiError::bad_request(
+            "The query is for a different organisation",
+        ));
+    }
+    let json = serde_json::to_string(&query).map_err(|e| ApiError::internal(&e.to_string()))?;
+    let q = to_quasr_query(query, settings)?;
+    check_metric_names(
+        &q,
+        &known_metric_names(conn, org_id, &settings.schema)?,
+        false,
+    )?;
+    Ok(json)
+}
+
+#[post("/orgs/<org_id>/queries", data = "<saved>")]
+pub fn create(
+    org_id: String,
+    saved: Json<NewSavedQuery>,
+    conn: DbConn,
+    key: ApiKey,
+    settings: State<Settings>,
+) -> Result<Created<Json<SavedQuery>>, ApiError> {
+    key.authorize(&org_id)?;
+    let NewSavedQuery { name, query } = saved.into_inner();
+    let query = validate(&conn, &settings, &org_id, query)?;
+    let saved = create_saved_query(&conn, &org_id, &name, &query).map_err(db_error)?;
+    Ok(Created(
+        format!("/orgs/{}/queries/{}", org_id, saved.id),
+        Some(Json(saved)),
+    ))
+}
+#[get("/orgs/<org_id>/queries")]
+pub fn list(org_id: String, conn: DbConn, key: ApiKey) -> Result<Json<Vec<SavedQuery>>, ApiError> {
+    key.authorize(&org_id)?;
+    list_saved_queries(&conn, &org_id)
+        .map(Json)
+        .map_err(db_error)
+}
+/// The saved query with its latest version, or the given one
+#[get("/orgs/<org_id>/queries/<id>?<version>")]
+pub fn show(
+    org_id: String,
+    id: String,
+    version: Option<i32>,
+    conn: DbConn,
+    key: ApiKey,
+) -> Result<Json<Value>, ApiError> {
+    key.authorize(&org_id)?;
+    let saved = load_saved_query(&conn, &org_id, &id)
+        .map_err(db_error)?
+        .ok_or_else(not_found)?;
+    let version = load_saved_query_version(&conn, &org_id, &id, version)
+        .map_err(db_error)?
+        .ok_or_else(|| ApiError::new(Status::NotFound, "No such version"))?;
+    Ok(Json(json!({ "savedQuery": saved, "version": version })))
+}
+#[get("/orgs/<org_id>/queries/<id>/versions")]
+pub fn versions(
+    org_id: String,
+    id: String,
+    conn: DbConn,
+    key: ApiKey,
+) -> Result<Json<Vec<SavedQueryVersion>>, ApiError> {
+    key.authorize(&org_id)?;
+    let versions = list_saved_query_versions(&conn, &org_id, &id).map_err(db_error)?;
+    if versions.is_empty() {
+        Err(not_found())
+    } else {
+        Ok(Json(versions))
+    }
+}
+/// Saves the query as the next version
+#[put("/orgs/<org_id>/queries/<id>", data = "<query>")]
+pub fn update(
+    org_id: String,
+    id: String,
+    query: Json<AdsFlowQuery>,
+    conn: DbConn,
+    key: ApiKey,
+    settings: State<Settings>,
+) -> Result<Json<SavedQuery>, ApiError> {
+    key.authorize(&org_id)?;
+    let query = validate(&conn, &settings, &org_id, query.into_inner())?;
+    add_saved_query_version(&conn, &org_id, &id, &query)
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(not_found)
+}
+#[delete("/orgs/<org_id>/queries/<id>")]
+pub fn remove(
+    org_id: String,
+    id: String,
+    conn: DbConn,
+    key: ApiKey,
+) -> Result<NoContent, ApiError> {
+    key.authorize(&org_id)?;
+    if delete_saved_query(&conn, &org_id, &id).map_err(db_error)? {
+        Ok(NoContent)
+    } else {
+        Err(not_found())
+    }
+}
+/// Answers the saved query like `POST /` would, optionally over a different date range
+#[get("/orgs/<org_id>/queries/<id>/run?<version>&<start_date>&<end_date>&<lenient>&<format>")]
+pub fn run(
+    org_id: String,
+    id: String,
+    version: Option<i32>,
+    start_date: Option<String>,
+    end_date: Option<String>,
+    lenient: Option<bool>,
+    format: Option<String>,
+    key: ApiKey,
+    _throttle: Throttle,
+    conn: DbConn,
+    settings: State<Settings>,
+    telemetry: State<Telemetry>,
+    request_id: RequestId,
+) -> Result<QSResponse, ApiError> {
+    key.authorize(&org_id)?;
+    let version = load_saved_query_version(&conn, &org_id, &id, version)
+        .map_err(db_error)?
+        .ok_or_else(not_found)?;
+    let mut query: AdsFlowQuery =
+        serde_json::from_str(&version.query).map_err(|e| ApiError::internal(&e.to_string()))?;
+    query.resolve_dates(settings.today(&org_id));
+    query.override_dates(parse_date(start_date)?, parse_date(end_date)?);
+    let q = to_quasr_query(query, &settings)?;
+    let _span = query_span(&request_id, &q).entered();
+    // The organisation's metrics may have changed since the query was saved
+    let (known, currency_metrics) = known_metrics(&conn, &org_id, &settings.schema)?;
+    let warnings = check_metric_names(&q, &known, lenient.unwrap_or(false))?;
+    let sql_query = sql_for(&q, &settings.schema);
+    let limits = &settings.org(&org_id).limits;
+    Ok(QSResponse {
+        r: answer_query(
+            &conn,
+            q.clone(),
+            sql_query,
+            &settings.schema,
+            limits,
+            &telemetry,
+        )?,
+        warnings,
+        query: q,
+        format,
+        currency_metrics,
+    })
+}
@@ -0,0 +1,225 @@
+use crate::rate_limit::RateLimit;
+use chrono::{NaiveDate, Weekday};
+use chrono_tz::Tz;
+use quasr_core::{
+    calendar::{CoreCalendar, CoreFiscalCalendar},
+    limits::CoreQueryLimits,
+    schema::SchemaMapping,
+};
+use quasr_io::clock::{Clock, SystemClock};
+use rocket::{
+    config::{Config, Value},
+    fairing::AdHoc,
+    Rocket,
+};
+use std::{collections::HashMap, fs};
+
+const DEFAULT_PACING_TOLERANCE: f64 = 0.1;
+
+#[derive(Clone)]
+pub struct OrgSettings {
+    /// Relative date ranges are resolved in this timezone
+    pub timezone: Tz,
+    pub calendar: CoreCalendar,
+    /// Fraction of the budget pacing reports allow projected spend to be off by
+    pub pacing_tolerance: f64,
+    pub limits: CoreQueryLimits,
+    pub rate_limit: RateLimit,
+}
+/// Organisation settings, read from `[global.orgs."<org id>"]` tables in `Rocket.toml`.
+/// Organisations without a table get the defaults from `[global]`.
+pub struct Settings {
+    default: OrgSettings,
+    orgs: HashMap<String, OrgSettings>,
+    clock: Box<dyn Clock>,
+    /// Tables and columns queries run against, the same for every organisation
+    pub schema: SchemaMapping,
+}
+fn get_str<'a>(table: &'a Value, key: &str) -> Result<Option<&'a str>, String> {
+    match table.get(key) {
+        Some(value) => value
+            .as_str()
+            .map(Some)
+            .ok_or_else(|| format!("{} has to be a string", key)),
+        None => Ok(None),
+    }
+}
+fn get_float(table: &Value, key: &str) -> Result<Option<f64>, String> {
+    match table.get(key) {
+        Some(value) => value
+            .as_float()
+            .or_else(|| value.as_integer().map(|i| i as f64))
+            .filter(|f| *f >= 0.0)
+            .map(Some)
+            .ok_or_else(|| format!("{} has to be a number that isn't negative", key)),
+        None => Ok(None),
+    }
+}
+fn get_int(table: &Value, key: &str) -> Result<Option<i64>, String> {
+    match table.get(key) {
+        Some(value) => value
+            .as_integer()
+            .filter(|i| *i > 0)
+            .map(Some)
+            .ok_or_else(|| format!("{} has to be a positive integer", key)),
+        None => Ok(None),
+    }
+}
+/// Reads the query limits, each of which overrides the one in `default`
+fn parse_limits(table: &Value, default: &CoreQueryLimits) -> Result<CoreQueryLimits, String> {
+    Ok(CoreQueryLimits {
+        max_days: get_int(table, "max_days")?.or(default.max_days),
+        max_days_campaign: get_int(table, "max_days_campaign")?.or(default.max_days_campaign),
+        max_days_ad_set: get_int(table, "max_days_ad_set")?.or(default.max_days_ad_set),
+        max_days_ad: get_int(table, "max_days_ad")?.or(default.max_days_ad),
+        max_rows: get_int(table, "max_rows")?
+            .map(|i| i as usize)
+            .or(default.max_rows),
+        max_execution_time_ms: get_int(table, "max_execution_time_ms")?
+            .map(|i| i as u64)
+            .or(default.max_execution_time_ms),
+    })
+}
+/// Reads the rate limits, each of which overrides the one in `default`
+fn parse_rate_limit(table: &Value, default: &RateLimit) -> Result<RateLimit, String> {
+    let requests_per_second = match get_float(table, "rate_limit_per_second")? {
+        Some(rate) if rate <= 0.0 => {
+            return Err("rate_limit_per_second has to be greater than 0".to_owned())
+        }
+        rate => rate.or(default.requests_per_second),
+    };
+    Ok(RateLimit {
+        requests_per_second,
+        burst: get_float(table, "rate_limit_burst")?.or(default.burst),
+        max_concurrent: get_int(table, "max_concurrent_queries")?
+            .map(|i| i as usize)
+            .or(default.max_concurrent),
+    })
+}
+/// `fiscal_periods` is the number of weeks of the periods of a quarter, e.g. "4-4-5"
+fn parse_fiscal_calendar(
+    year_start: &str,
+    periods: Option<&str>,
+) -> Result<CoreFiscalCalendar, String> {
+    let year_start = NaiveDate::parse_from_str(year_start, "%Y-%m-%d")
+        .map_err(|_| "fiscal_year_start has to be a YYYY-MM-DD date".to_owned())?;
+    let weeks = periods
+        .unwrap_or("4-4-5")
+        .split('-')
+        .map(|w| w.parse::<i64>().ok().filter(|w| *w > 0))
+        .collect::<Option<Vec<i64>>>()
+        .filter(|w| w.iter().sum::<i64>() == 13)
+        .ok_or_else(|| "fiscal_periods has to be three periods of 13 weeks in total".to_owned())?;
+    Ok(CoreFiscalCalendar {
+        year_start,
+        weeks_per_period: [weeks[0], weeks[1], weeks[2]],
+    })
+}
+/// Reads the `[global.schema]` table, or the TOML file `schema_file` points to
+fn parse_schema(config: &Config) -> Result<SchemaMapping, String> {
+    let value = match (config.get_table("schema"), config.get_str("schema_file")) {
+        (Ok(_), Ok(_)) => return Err("set either schema or schema_file, not both".to_owned()),
+        (Ok(table), Err(_)) => Value::Table(table.clone()),
+        (Err(_), Ok(path)) => fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| file.parse::<Value>().map_err(|e| e.to_string()))
+            .map_err(|e| format!("schema_file {}: {}", path, e))?,
+        (Err(_), Err(_)) => return Ok(SchemaMapping::default()),
+    };
+    let schema: SchemaMapping = value.try_into().map_err(|e| format!("schema: {}", e))?;
+    schema.validate().map_err(|e| format!("schema: {}", e))?;
+    Ok(schema)
+}
+/// Reads the settings in `table`, falling back to `default` for the missing ones
+fn parse_org_settings(table: &Value, default: &OrgSettings) -> Result<OrgSettings, String> {
+    let timezone = match get_str(table, "timezone")? {
+        Some(timezone) => timezone.parse()?,
+        None => default.timezone,
+    };
+    let week_start = match get_str(table, "week_start")? {
+        Some(day) => day
+            .parse::<Weekday>()
+            .map_err(|_| format!("{} is not a day of the week", day))?,
+        None => default.calendar.week_start,
+    };
+    let fiscal = match get_str(table, "fiscal_year_start")? {
+        Some(year_start) => Some(parse_fiscal_calendar(
+            year_start,
+            get_str(table, "fiscal_periods")?,
+        )?),
+        None => default.calendar.fiscal.clone(),
+    };
+    Ok(OrgSettings {
+        timezone,
+        calendar: CoreCalendar { week_start, fiscal },
+        pacing_tolerance: get_float(table, "pacing_tolerance")?.unwrap_or(default.pacing_tolerance),
+        limits: parse_limits(table, &default.limits)?,
+        rate_limit: parse_rate_limit(table, &default.rate_limit)?,
+    })
+}
+impl Settings {
+    pub fn new(
+        default: OrgSettings,
+        orgs: HashMap<String, OrgSettings>,
+        clock: Box<dyn Clock>,
+        schema: SchemaMapping,
+    ) -> Self {
+        Settings {
+            default,
+            orgs,
+            clock,
+            schema,
+        }
+    }
+    pub fn org(&self, org_id: &str) -> &OrgSettings {
+        self.orgs.get(org_id).unwrap_or(&self.default)
+    }
+    /// Limits of the requests that aren't for an organisation in particular
+    pub fn default_rate_limit(&self) -> &RateLimit {
+        &self.default.rate_limit
+    }
+    /// The current day in the organisation's timezone
+    pub fn today(&self, org_id: &str) -> NaiveDate {
+        self.clock.today(self.org(org_id).timezone)
+    }
+    fn from_rocket(rocket: &Rocket) -> Result<Self, String> {
+        let config = rocket.config();
+        let global = Value::Table(config.extras.clone().into_iter().collect());
+        let default = parse_org_settings(
+            &global,
+            &OrgSettings {
+                timezone: Tz::UTC,
+                calendar: CoreCalendar::default(),
+                pacing_tolerance: DEFAULT_PACING_TOLERANCE,
+                limits: CoreQueryLimits::default(),
+                rate_limit: RateLimit::default(),
+            },
+        )?;
+        let mut orgs = HashMap::new();
+        if let Ok(table) = config.get_table("orgs") {
+            for (org_id, org) in table {
+                let settings = parse_org_settings(org, &default)
+                    .map_err(|e| format!("orgs.{}: {}", org_id, e))?;
+                orgs.insert(org_id.clone(), settings);
+            }
+        }
+        Ok(Settings::new(
+            default,
+            orgs,
+            Box::new(SystemClock),
+            parse_schema(config)?,
+        ))
+    }
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_attach(
+            "Organisation settings",
+            |rocket| match Settings::from_rocket(&rocket) {
+                Ok(settings) => Ok(rocket.manage(settings)),
+                Err(e) => {
+                    eprintln!("Invalid settings: {}", e);
+                    Err(rocket)
+                }
+            },
+        )
+    }
+}
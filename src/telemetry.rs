@@ -0,0 +1,200 @@
+use crate::DbConnPool;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::ContentType,
+    response::content::Content,
+    Data, Request, Response, Rocket, State,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+const ROW_BUCKETS: &[f64] = &[10.0, 100.0, 1e3, 1e4, 1e5, 1e6, 1e7];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    /// Running the query in the database, `load_query_from_db`
+    Sql,
+    /// Computing the metrics from the rows, `metrics_to_indexed_metrics`
+    Processing,
+}
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+    fn observe(&mut self, value: f64) {
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+    /// `labels` are the histogram's own labels, e.g. `phase="sql",`
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter()) {
+            writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bucket, count
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        )
+        .unwrap();
+        let labels = labels.trim_end_matches(',');
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+    }
+}
+struct Registry {
+    requests: BTreeMap<u16, u64>,
+    latency: Histogram,
+    sql: Histogram,
+    processing: Histogram,
+    rows: Histogram,
+}
+/// Counters and histograms of the server, exposed at `/metrics`. Clones share the same values,
+/// so the job workers can record into it as well.
+#[derive(Clone)]
+pub struct Telemetry(Arc<Mutex<Registry>>);
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry(Arc::new(Mutex::new(Registry {
+            requests: BTreeMap::new(),
+            latency: Histogram::new(LATENCY_BUCKETS),
+            sql: Histogram::new(LATENCY_BUCKETS),
+            processing: Histogram::new(LATENCY_BUCKETS),
+            rows: Histogram::new(ROW_BUCKETS),
+        })))
+    }
+}
+/// When the request came in, kept in the request's local cache
+struct RequestStart(Instant);
+
+impl Telemetry {
+    /// Runs `f`, recording how long it took as time spent in `phase`
+    pub fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let ret = f();
+        let seconds = start.elapsed().as_secs_f64();
+        let mut registry = self.0.lock().unwrap();
+        match phase {
+            Phase::Sql => registry.sql.observe(seconds),
+            Phase::Processing => registry.processing.observe(seconds),
+        }
+        ret
+    }
+    /// Records how many rows the database returned for a query
+    pub fn observe_rows(&self, rows: usize) {
+        self.0.lock().unwrap().rows.observe(rows as f64);
+    }
+    fn render(&self, pool: &DbConnPool) -> String {
+        let registry = self.0.lock().unwrap();
+        let mut out = String::new();
+        out.push_str("# HELP quasr_requests_total HTTP requests answered, by status\n");
+        out.push_str("# TYPE quasr_requests_total counter\n");
+        for (status, count) in &registry.requests {
+            writeln!(
+                out,
+                "quasr_requests_total{{status=\"{}\"}} {}",
+                status, count
+            )
+            .unwrap();
+        }
+        out.push_str("# HELP quasr_request_duration_seconds Time to answer HTTP requests\n");
+        out.push_str("# TYPE quasr_request_duration_seconds histogram\n");
+        registry
+            .latency
+            .write(&mut out, "quasr_request_duration_seconds", "");
+        out.push_str(
+            "# HELP quasr_phase_duration_seconds Time spent answering queries, by phase\n",
+        );
+        out.push_str("# TYPE quasr_phase_duration_seconds histogram\n");
+        registry
+            .sql
+            .write(&mut out, "quasr_phase_duration_seconds", "phase=\"sql\",");
+        registry.processing.write(
+            &mut out,
+            "quasr_phase_duration_seconds",
+            "phase=\"processing\",",
+        );
+        out.push_str("# HELP quasr_query_rows Rows the database returned per query\n");
+        out.push_str("# TYPE quasr_query_rows histogram\n");
+        registry.rows.write(&mut out, "quasr_query_rows", "");
+        // There is no query cache, so there is no hit ratio to report
+        let state = pool.0.state();
+        out.push_str("# HELP quasr_db_pool_connections Database connections, by state\n");
+        out.push_str("# TYPE quasr_db_pool_connections gauge\n");
+        writeln!(
+            out,
+            "quasr_db_pool_connections{{state=\"idle\"}} {}",
+            state.idle_connections
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "quasr_db_pool_connections{{state=\"in_use\"}} {}",
+            state.connections - state.idle_connections
+        )
+        .unwrap();
+        out.push_str("# HELP quasr_db_pool_max_connections Size of the database pool\n");
+        out.push_str("# TYPE quasr_db_pool_max_connections gauge\n");
+        writeln!(out, "quasr_db_pool_max_connections {}", pool.0.max_size()).unwrap();
+        out
+    }
+}
+impl Fairing for Telemetry {
+    fn info(&self) -> Info {
+        Info {
+            name: "Telemetry",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.manage(self.clone()))
+    }
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        let mut registry = self.0.lock().unwrap();
+        *registry.requests.entry(response.status().code).or_insert(0) += 1;
+        registry.latency.observe(start.0.elapsed().as_secs_f64());
+    }
+}
+
+/// Server metrics in the Prometheus text format. Nothing in them is specific to an
+/// organisation, so no API key is needed.
+#[get("/metrics")]
+pub fn metrics(telemetry: State<Telemetry>, pool: State<DbConnPool>) -> Content<String> {
+    Content(
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        telemetry.render(&pool),
+    )
+}